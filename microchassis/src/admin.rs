@@ -0,0 +1,309 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Standalone admin server (feature `admin-server`).
+//!
+//! [`spawn`] starts a thread with a current-thread tokio runtime that serves
+//! a [`Router`] over TCP or a Unix domain socket. Sampling is disabled on the
+//! server's threads (`thread.prof.active`), so its own allocations, e.g. for
+//! dumps, don't show up in profiles.
+
+use crate::profiling::{
+    jeprof::{Router, RouterBuilder},
+    mallctl,
+};
+use std::{fmt, io, net::SocketAddr, sync::mpsc, thread};
+#[cfg(unix)]
+use std::{fs, os::unix::fs::FileTypeExt as _, path::PathBuf};
+use tokio::sync::oneshot;
+
+/// Where the admin server listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// A stale socket file is replaced, one still accepting connections is
+    /// [`io::ErrorKind::AddrInUse`]. The file is removed on shutdown. Requests
+    /// have no client address, so address-based auth rejects them.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<PathBuf> for ListenAddr {
+    fn from(path: PathBuf) -> Self {
+        Self::Unix(path)
+    }
+}
+
+/// Configures [`spawn`].
+pub struct AdminConfig {
+    /// Defaults to `127.0.0.1:6060`.
+    pub addr: ListenAddr,
+    /// Defaults to all endpoints under `/pprof`.
+    pub router: Router,
+    /// Defaults to `pprof`.
+    pub thread_name: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            addr: ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 6060))),
+            router: RouterBuilder::new().build(),
+            thread_name: "pprof".to_owned(),
+        }
+    }
+}
+
+/// Starts the admin server on its own thread. Returns once the listener is
+/// bound, or with the error if it can't be.
+pub fn spawn(config: AdminConfig) -> io::Result<AdminHandle> {
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let thread = thread::Builder::new().name(config.thread_name.clone()).spawn(move || {
+        run(config, &ready_tx, stop_rx);
+    })?;
+    match ready_rx.recv() {
+        Ok(Ok(local_addr)) => {
+            Ok(AdminHandle { local_addr, stop: Some(stop_tx), thread: Some(thread) })
+        }
+        Ok(Err(e)) => {
+            let _ = thread.join();
+            Err(e)
+        }
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "admin server thread died")),
+    }
+}
+
+fn disable_sampling() {
+    // Fails if profiling is disabled, nothing to do then.
+    let _ = mallctl::set_thread_active(false);
+}
+
+fn run(
+    config: AdminConfig,
+    ready: &mpsc::Sender<io::Result<ListenAddr>>,
+    stop: oneshot::Receiver<()>,
+) {
+    disable_sampling();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        // Blocking pool threads run the handlers.
+        .on_thread_start(disable_sampling)
+        .build();
+    let rt = match rt {
+        Ok(rt) => rt,
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+    let app = config.router.axum_router();
+    let shutdown = async {
+        // Also resolves if the handle is gone.
+        let _ = stop.await;
+    };
+    let result = rt.block_on(async move {
+        match config.addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = match tokio::net::TcpListener::bind(addr).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        let _ = ready.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                let local_addr = listener.local_addr().map(ListenAddr::Tcp);
+                if ready.send(local_addr).is_err() {
+                    return Ok(());
+                }
+                let service = app.into_make_service_with_connect_info::<SocketAddr>();
+                axum::serve(listener, service).with_graceful_shutdown(shutdown).await
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let listener = match bind_unix(&path) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        let _ = ready.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                let result = if ready.send(Ok(ListenAddr::Unix(path.clone()))).is_ok() {
+                    axum::serve(listener, app.into_make_service())
+                        .with_graceful_shutdown(shutdown)
+                        .await
+                } else {
+                    Ok(())
+                };
+                let _ = fs::remove_file(&path);
+                result
+            }
+        }
+    });
+    if let Err(e) = result {
+        tracing::warn!("admin: server failed: {e}");
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    if fs::symlink_metadata(path).map_or(false, |m| m.file_type().is_socket()) {
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                let msg = format!("{} is in use", path.display());
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+            }
+            // Nobody listening anymore.
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(e) => return Err(e),
+        }
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+/// Shuts the server down gracefully when dropped.
+pub struct AdminHandle {
+    local_addr: ListenAddr,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl AdminHandle {
+    /// The bound address, with the actual port if port 0 was requested.
+    #[must_use]
+    pub fn local_addr(&self) -> &ListenAddr {
+        &self.local_addr
+    }
+
+    /// Stops accepting connections and waits for in-flight requests and the
+    /// server thread to finish.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for AdminHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiling::{
+        backend::MockBackend,
+        jeprof::{ErrorResponse, Reply},
+    };
+    use http::Method;
+    use std::{net::TcpStream, sync::Arc};
+
+    fn router() -> Router {
+        RouterBuilder::new()
+            .backend(Arc::new(MockBackend::default()))
+            .handler(Method::GET, "/sampled", |_, _, _| {
+                let active =
+                    mallctl::thread_active().map_err(|e| ErrorResponse::new(e.to_string()))?;
+                Ok(Reply::text(format!("{active}\r\n")))
+            })
+            .build()
+    }
+
+    fn get<S: io::Read + io::Write>(mut stream: S, path: &str) -> String {
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: admin\r\nConnection: close\r\n\r\n")
+            .expect("write");
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).expect("read");
+        resp
+    }
+
+    #[test]
+    fn test_tcp() {
+        let config = AdminConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)).into(),
+            router: router(),
+            ..AdminConfig::default()
+        };
+        let handle = spawn(config).expect("spawn");
+        let addr = match handle.local_addr() {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            #[cfg(unix)]
+            ListenAddr::Unix(_) => None,
+        }
+        .expect("tcp");
+        assert_ne!(0, addr.port());
+
+        let resp = get(TcpStream::connect(addr).expect("connect"), "/pprof/conf");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        assert!(resp.ends_with("\r\n\r\nprof.active:false,prof.lg_sample:19\r\n"), "{resp}");
+        let resp = get(TcpStream::connect(addr).expect("connect"), "/pprof/sampled");
+        assert!(resp.ends_with("\r\n\r\nfalse\r\n"), "{resp}");
+
+        let config = AdminConfig { addr: addr.into(), ..AdminConfig::default() };
+        assert_eq!(io::ErrorKind::AddrInUse, spawn(config).err().expect("in use").kind());
+
+        handle.shutdown();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("admin.sock");
+        // Leaves a stale socket file behind.
+        drop(std::os::unix::net::UnixListener::bind(&path).expect("bind"));
+        let config = || AdminConfig {
+            addr: path.clone().into(),
+            router: router(),
+            thread_name: "admin".to_owned(),
+        };
+        let handle = spawn(config()).expect("spawn");
+        assert_eq!(format!("unix:{}", path.display()), handle.local_addr().to_string());
+
+        assert_eq!(io::ErrorKind::AddrInUse, spawn(config()).err().expect("in use").kind());
+        let stream = std::os::unix::net::UnixStream::connect(&path).expect("connect");
+        let resp = get(stream, "/pprof/conf");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+
+        drop(handle);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An allocator layer enforcing a memory budget.
+//!
+//! Live bytes are counted on every allocation. Crossing the soft limit calls
+//! registered callbacks, exceeding the hard limit makes the allocation fail.
+//! Stacked below [`crate::oompanic::OomPanic`] a failed allocation panics,
+//! so OOM handling triggers long before the kernel OOM killer does:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: Stacked<OomPanic, BudgetAllocator<Jemalloc>> =
+//!     Stacked::new(Budget::new(), Jemalloc).with(OomPanic);
+//!
+//! ALLOC.inner().layer().set_hard_limit(Some(1 << 30));
+//! ```
+
+use crate::{
+    cgroup::{self, Cgroup, Watermark},
+    layer::{self, Layer, Stacked},
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use std::sync::RwLock;
+
+/// Passed to soft limit callbacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SoftLimitExceeded {
+    /// Live bytes including the allocation that crossed the limit.
+    pub live: usize,
+    pub limit: usize,
+}
+
+type Callback = Box<dyn Fn(&SoftLimitExceeded) + Send + Sync>;
+
+const UNLIMITED: usize = usize::MAX;
+
+/// A global allocator wrapper with a [`Budget`].
+pub type BudgetAllocator<T> = Stacked<Budget, T>;
+
+/// An allocator layer with a soft and a hard limit on live bytes.
+pub struct Budget {
+    live: AtomicUsize,
+    soft_limit: AtomicUsize,
+    hard_limit: AtomicUsize,
+    soft_limit_exceeded: AtomicU64,
+    hard_limit_rejected: AtomicU64,
+    callbacks: RwLock<Vec<Callback>>,
+}
+
+impl Budget {
+    /// Creates a budget without any limits set.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            live: AtomicUsize::new(0),
+            soft_limit: AtomicUsize::new(UNLIMITED),
+            hard_limit: AtomicUsize::new(UNLIMITED),
+            soft_limit_exceeded: AtomicU64::new(0),
+            hard_limit_rejected: AtomicU64::new(0),
+            callbacks: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Returns the number of bytes currently allocated through this layer.
+    #[inline]
+    pub fn live_bytes(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// Sets the limit above which soft limit callbacks are called.
+    #[inline]
+    pub fn set_soft_limit(&self, limit: Option<usize>) {
+        self.soft_limit.store(limit.unwrap_or(UNLIMITED), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn soft_limit(&self) -> Option<usize> {
+        Some(self.soft_limit.load(Ordering::Relaxed)).filter(|&l| l != UNLIMITED)
+    }
+
+    /// Sets the soft limit to `watermark`, e.g. 80% of the cgroup limit.
+    /// Percentages are resolved once and unset the limit if the cgroup has
+    /// none. Returns the limit set.
+    pub fn set_soft_limit_watermark(
+        &self,
+        cgroup: &Cgroup,
+        watermark: Watermark,
+    ) -> Result<Option<usize>, cgroup::Error> {
+        let limit = resolve(cgroup, watermark)?;
+        self.set_soft_limit(limit);
+        Ok(limit)
+    }
+
+    /// Sets the limit above which allocations fail.
+    #[inline]
+    pub fn set_hard_limit(&self, limit: Option<usize>) {
+        self.hard_limit.store(limit.unwrap_or(UNLIMITED), Ordering::Relaxed);
+    }
+
+    /// Like [`Budget::set_soft_limit_watermark`] for the hard limit.
+    pub fn set_hard_limit_watermark(
+        &self,
+        cgroup: &Cgroup,
+        watermark: Watermark,
+    ) -> Result<Option<usize>, cgroup::Error> {
+        let limit = resolve(cgroup, watermark)?;
+        self.set_hard_limit(limit);
+        Ok(limit)
+    }
+
+    #[inline]
+    pub fn hard_limit(&self) -> Option<usize> {
+        Some(self.hard_limit.load(Ordering::Relaxed)).filter(|&l| l != UNLIMITED)
+    }
+
+    /// Returns how often the soft limit was crossed.
+    #[inline]
+    pub fn soft_limit_exceeded(&self) -> u64 {
+        self.soft_limit_exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many allocations failed due to the hard limit.
+    #[inline]
+    pub fn hard_limit_rejected(&self) -> u64 {
+        self.hard_limit_rejected.load(Ordering::Relaxed)
+    }
+
+    /// Registers a callback called whenever live bytes cross the soft limit.
+    ///
+    /// Callbacks run on the allocating thread while it is inside the
+    /// allocator and should return quickly. They run inside
+    /// [`layer::guarded`]: allocations made by a callback are counted but
+    /// don't trigger callbacks.
+    pub fn on_soft_limit<F>(&self, f: F)
+    where
+        F: Fn(&SoftLimitExceeded) + Send + Sync + 'static,
+    {
+        let f: Callback = Box::new(f);
+        layer::guarded(|| {
+            self.callbacks.write().unwrap_or_else(std::sync::PoisonError::into_inner).push(f);
+        });
+    }
+
+    // Accounts for `size` more bytes. Returns false if over the hard limit.
+    #[inline]
+    fn grow(&self, size: usize) -> bool {
+        let hard_limit = self.hard_limit.load(Ordering::Relaxed);
+        // Only commits within the limit, a rejected allocation must not
+        // count against concurrent ones.
+        let result = self.live.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |prev| {
+            Some(prev.saturating_add(size)).filter(|&live| live <= hard_limit)
+        });
+        let Ok(prev) = result else {
+            self.hard_limit_rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+        let live = prev.saturating_add(size);
+        let limit = self.soft_limit.load(Ordering::Relaxed);
+        if prev <= limit && live > limit {
+            self.soft_limit_exceeded.fetch_add(1, Ordering::Relaxed);
+            self.notify(&SoftLimitExceeded { live, limit });
+        }
+        true
+    }
+
+    #[inline]
+    fn shrink(&self, size: usize) {
+        self.live.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn notify(&self, event: &SoftLimitExceeded) {
+        if layer::is_reentrant() {
+            return;
+        }
+        layer::guarded(|| {
+            let callbacks =
+                self.callbacks.read().unwrap_or_else(std::sync::PoisonError::into_inner);
+            for f in callbacks.iter() {
+                f(event);
+            }
+        });
+    }
+}
+
+fn resolve(cgroup: &Cgroup, watermark: Watermark) -> Result<Option<usize>, cgroup::Error> {
+    Ok(cgroup.resolve(watermark)?.map(|bytes| usize::try_from(bytes).unwrap_or(UNLIMITED)))
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(unsafe_code)]
+unsafe impl Layer for Budget {
+    #[inline]
+    unsafe fn alloc<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        if !self.grow(layout.size()) {
+            return core::ptr::null_mut();
+        }
+        let ptr = inner.alloc(layout);
+        if ptr.is_null() {
+            self.shrink(layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        if !self.grow(layout.size()) {
+            return core::ptr::null_mut();
+        }
+        let ptr = inner.alloc_zeroed(layout);
+        if ptr.is_null() {
+            self.shrink(layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn realloc<A: GlobalAlloc>(
+        &self,
+        inner: &A,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let old_size = layout.size();
+        if new_size > old_size && !self.grow(new_size - old_size) {
+            return core::ptr::null_mut();
+        }
+        let new_ptr = inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            if new_size > old_size {
+                self.shrink(new_size - old_size);
+            }
+        } else if new_size < old_size {
+            self.shrink(old_size - new_size);
+        }
+        new_ptr
+    }
+
+    #[inline]
+    unsafe fn dealloc<A: GlobalAlloc>(&self, inner: &A, ptr: *mut u8, layout: Layout) {
+        inner.dealloc(ptr, layout);
+        self.shrink(layout.size());
+    }
+}
+
+#[cfg(test)]
+#[allow(unsafe_code)]
+mod tests {
+    use super::*;
+    use std::{alloc::System, sync::Arc};
+
+    #[test]
+    fn test_hard_limit() {
+        let alloc = BudgetAllocator::new(Budget::new(), System);
+        let budget = alloc.layer();
+        budget.set_hard_limit(Some(1024));
+        let layout = Layout::from_size_align(512, 8).expect("layout");
+
+        unsafe {
+            let a = alloc.alloc(layout);
+            assert!(!a.is_null());
+            let b = alloc.alloc_zeroed(layout);
+            assert!(!b.is_null());
+            assert_eq!(1024, budget.live_bytes());
+            assert!(alloc.alloc(layout).is_null());
+            assert!(alloc.realloc(a, layout, 513).is_null());
+            assert_eq!(2, budget.hard_limit_rejected());
+
+            let a = alloc.realloc(a, layout, 256);
+            assert!(!a.is_null());
+            assert_eq!(768, budget.live_bytes());
+            alloc.dealloc(a, Layout::from_size_align(256, 8).expect("layout"));
+            alloc.dealloc(b, layout);
+        }
+        assert_eq!(0, budget.live_bytes());
+    }
+
+    #[test]
+    fn test_rejected_not_counted() {
+        let alloc = BudgetAllocator::new(Budget::new(), System);
+        let budget = alloc.layer();
+        budget.set_hard_limit(Some(1024));
+        let small = Layout::from_size_align(256, 8).expect("layout");
+        let large = Layout::from_size_align(1025, 8).expect("layout");
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100_000 {
+                        let ptr = unsafe { alloc.alloc(small) };
+                        assert!(!ptr.is_null());
+                        unsafe { alloc.dealloc(ptr, small) };
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..100_000 {
+                    assert!(unsafe { alloc.alloc(large) }.is_null());
+                }
+            });
+        });
+        assert_eq!((0, 100_000), (budget.live_bytes(), budget.hard_limit_rejected()));
+    }
+
+    #[test]
+    fn test_soft_limit_callbacks() {
+        let alloc = BudgetAllocator::new(Budget::new(), System);
+        let budget = alloc.layer();
+        budget.set_soft_limit(Some(1000));
+        let seen = Arc::new(AtomicUsize::new(0));
+        let seen2 = Arc::clone(&seen);
+        budget.on_soft_limit(move |event| {
+            assert_eq!(1000, event.limit);
+            seen2.store(event.live, Ordering::Relaxed);
+        });
+        let layout = Layout::from_size_align(600, 8).expect("layout");
+
+        unsafe {
+            let a = alloc.alloc(layout);
+            assert_eq!(0, seen.load(Ordering::Relaxed));
+            let b = alloc.alloc(layout);
+            assert_eq!(1200, seen.load(Ordering::Relaxed));
+            alloc.dealloc(b, layout);
+            let b = alloc.alloc(layout);
+            alloc.dealloc(b, layout);
+            alloc.dealloc(a, layout);
+        }
+        assert_eq!(2, budget.soft_limit_exceeded());
+    }
+
+    #[test]
+    fn test_watermark_limits() {
+        let root = crate::cgroup::tests::fake_root("max\n", "2000\n");
+        let cg = Cgroup::discover_in(root.path()).expect("discover");
+        let budget = Budget::new();
+        let soft = budget.set_soft_limit_watermark(&cg, Watermark::PercentOfLimit(80.0));
+        assert_eq!(Some(1600), soft.expect("soft limit"));
+        let hard = budget.set_hard_limit_watermark(&cg, Watermark::Bytes(1900));
+        assert_eq!(Some(1900), hard.expect("hard limit"));
+        assert_eq!((Some(1600), Some(1900)), (budget.soft_limit(), budget.hard_limit()));
+
+        let root = crate::cgroup::tests::fake_root("max\n", "max\n");
+        let cg = Cgroup::discover_in(root.path()).expect("discover");
+        let soft = budget.set_soft_limit_watermark(&cg, Watermark::PercentOfLimit(80.0));
+        assert_eq!(None, soft.expect("soft limit"));
+        assert_eq!(None, budget.soft_limit());
+    }
+}
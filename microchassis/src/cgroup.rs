@@ -0,0 +1,324 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads memory limits and usage of the process's cgroup v2.
+//!
+//! Based on <https://docs.kernel.org/admin-guide/cgroup-v2.html#memory-interface-files>.
+
+use serde::Serialize;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+const PROC_SELF_CGROUP: &str = "proc/self/cgroup";
+const CGROUP_MOUNT: &str = "sys/fs/cgroup";
+
+/// The cgroup v2 the current process belongs to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cgroup {
+    mount: PathBuf,
+    dir: PathBuf,
+}
+
+/// Counters from `memory.events`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryEvents {
+    pub low: u64,
+    pub high: u64,
+    pub max: u64,
+    pub oom: u64,
+    pub oom_kill: u64,
+}
+
+/// Memory interface values of a cgroup. Limits are `None` if set to `max`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryStats {
+    pub max: Option<u64>,
+    pub high: Option<u64>,
+    pub current: u64,
+    pub events: MemoryEvents,
+}
+
+/// A memory threshold, either absolute or relative to the cgroup limit.
+///
+/// Accepted by [`crate::budget::Budget::set_soft_limit_watermark`] and
+/// [`crate::psi::Watcher::watermark`]; [`Cgroup::resolve`] converts it to bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Watermark {
+    Bytes(u64),
+    /// Percentage (0-100) of the effective `memory.max`.
+    PercentOfLimit(f64),
+}
+
+impl Cgroup {
+    /// Discovers the cgroup of the current process.
+    #[inline]
+    pub fn discover() -> Result<Self, Error> {
+        Self::discover_in("/")
+    }
+
+    /// Discovers the cgroup with `root` as filesystem root, i.e. reads
+    /// `<root>/proc/self/cgroup` and uses `<root>/sys/fs/cgroup`.
+    pub fn discover_in<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
+        let root = root.as_ref();
+        let content = fs::read_to_string(root.join(PROC_SELF_CGROUP))?;
+        let path = parse_proc_cgroup(&content).ok_or(Error::NotFound)?;
+        let mount = root.join(CGROUP_MOUNT);
+        let dir = mount.join(path.trim_start_matches('/'));
+        if !dir.join("memory.current").exists() {
+            return Err(Error::NoMemoryController(dir));
+        }
+        Ok(Self { mount, dir })
+    }
+
+    /// Returns the directory of this cgroup.
+    #[must_use]
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the path of `memory.pressure`, see [`crate::psi`].
+    #[must_use]
+    #[inline]
+    pub fn memory_pressure_path(&self) -> PathBuf {
+        self.dir.join("memory.pressure")
+    }
+
+    /// Reads `memory.max`.
+    #[inline]
+    pub fn memory_max(&self) -> Result<Option<u64>, Error> {
+        read_limit(&self.dir.join("memory.max"))
+    }
+
+    /// Reads `memory.high`.
+    #[inline]
+    pub fn memory_high(&self) -> Result<Option<u64>, Error> {
+        read_limit(&self.dir.join("memory.high"))
+    }
+
+    /// Reads `memory.current`.
+    #[inline]
+    pub fn memory_current(&self) -> Result<u64, Error> {
+        let path = self.dir.join("memory.current");
+        parse_u64(&path, fs::read_to_string(&path)?.trim())
+    }
+
+    /// Reads `memory.events`.
+    pub fn memory_events(&self) -> Result<MemoryEvents, Error> {
+        let path = self.dir.join("memory.events");
+        let mut events = MemoryEvents::default();
+        for line in fs::read_to_string(&path)?.lines() {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            let field = match key {
+                "low" => &mut events.low,
+                "high" => &mut events.high,
+                "max" => &mut events.max,
+                "oom" => &mut events.oom,
+                "oom_kill" => &mut events.oom_kill,
+                _ => continue,
+            };
+            *field = parse_u64(&path, value)?;
+        }
+        Ok(events)
+    }
+
+    /// Reads all memory interface values.
+    pub fn memory(&self) -> Result<MemoryStats, Error> {
+        Ok(MemoryStats {
+            max: self.memory_max()?,
+            high: self.memory_high()?,
+            current: self.memory_current()?,
+            events: self.memory_events()?,
+        })
+    }
+
+    /// Returns the lowest `memory.max` of this cgroup and its ancestors up
+    /// to the mount point, which is the root of a container's cgroup
+    /// namespace. Levels without `memory.max`, i.e. the host root, are
+    /// skipped.
+    pub fn effective_memory_max(&self) -> Result<Option<u64>, Error> {
+        let mut limit: Option<u64> = None;
+        let mut dir = Some(self.dir.as_path());
+        while let Some(d) = dir.filter(|d| d.starts_with(&self.mount)) {
+            let max = match read_limit(&d.join("memory.max")) {
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => None,
+                max => max?,
+            };
+            if let Some(max) = max {
+                limit = Some(limit.map_or(max, |l| l.min(max)));
+            }
+            dir = d.parent();
+        }
+        Ok(limit)
+    }
+
+    /// Converts `watermark` into bytes. Returns `None` for a percentage if
+    /// there is no effective limit.
+    pub fn resolve(&self, watermark: Watermark) -> Result<Option<u64>, Error> {
+        match watermark {
+            Watermark::Bytes(bytes) => Ok(Some(bytes)),
+            Watermark::PercentOfLimit(percent) => {
+                Ok(self.effective_memory_max()?.map(|max| percent_of(max, percent)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Watermark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => write!(f, "{bytes}"),
+            Self::PercentOfLimit(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn limit(v: Option<u64>) -> String {
+            v.map_or_else(|| "max".to_owned(), |v| v.to_string())
+        }
+        writeln!(f, "memory.max:{}", limit(self.max))?;
+        writeln!(f, "memory.high:{}", limit(self.high))?;
+        writeln!(f, "memory.current:{}", self.current)?;
+        writeln!(f, "memory.events.low:{}", self.events.low)?;
+        writeln!(f, "memory.events.high:{}", self.events.high)?;
+        writeln!(f, "memory.events.max:{}", self.events.max)?;
+        writeln!(f, "memory.events.oom:{}", self.events.oom)?;
+        write!(f, "memory.events.oom_kill:{}", self.events.oom_kill)
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss, clippy::cast_sign_loss)]
+fn percent_of(value: u64, percent: f64) -> u64 {
+    (value as f64 * percent.clamp(0.0, 100.0) / 100.0) as u64
+}
+
+// Finds the unified hierarchy entry `0::<path>`.
+fn parse_proc_cgroup(content: &str) -> Option<&str> {
+    content.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+fn read_limit(path: &Path) -> Result<Option<u64>, Error> {
+    match fs::read_to_string(path)?.trim() {
+        "max" => Ok(None),
+        value => parse_u64(path, value).map(Some),
+    }
+}
+
+fn parse_u64(path: &Path, value: &str) -> Result<u64, Error> {
+    value.parse().map_err(|_| Error::Parse(path.to_owned(), value.to_owned()))
+}
+
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error {
+    #[error("cgroup: no cgroup v2 entry found")]
+    NotFound,
+
+    #[error("cgroup: memory controller not available in {0:?}")]
+    NoMemoryController(PathBuf),
+
+    #[error("cgroup: cannot parse {1:?} in {0:?}")]
+    Parse(PathBuf, String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A root with `/proc/self/cgroup` and the cgroup `app.slice/svc.service`
+    /// using 1MiB.
+    pub fn fake_root(max: &str, parent_max: &str) -> tempfile::TempDir {
+        let root = tempfile::tempdir().expect("tempdir");
+        let write = |path: &str, content: &str| write(&root, path, content);
+        write(PROC_SELF_CGROUP, "1:name=systemd:/ignored\n0::/app.slice/svc.service\n");
+        let dir = "sys/fs/cgroup/app.slice/svc.service";
+        write(&format!("{dir}/memory.max"), max);
+        write(&format!("{dir}/memory.high"), "max\n");
+        write(&format!("{dir}/memory.current"), "1048576\n");
+        write(&format!("{dir}/memory.events"), "low 0\nhigh 2\nmax 3\noom 1\noom_kill 1\n");
+        write("sys/fs/cgroup/app.slice/memory.max", parent_max);
+        root
+    }
+
+    fn write(root: &tempfile::TempDir, path: &str, content: &str) {
+        let path = root.path().join(path);
+        fs::create_dir_all(path.parent().expect("parent")).expect("create_dir_all");
+        fs::write(path, content).expect("write");
+    }
+
+    #[test]
+    fn test_memory() {
+        let root = fake_root("4194304\n", "max\n");
+        let cg = Cgroup::discover_in(root.path()).expect("discover");
+        assert!(cg.path().ends_with("app.slice/svc.service"));
+
+        let stats = cg.memory().expect("memory");
+        assert_eq!(
+            MemoryStats {
+                max: Some(4 << 20),
+                high: None,
+                current: 1 << 20,
+                events: MemoryEvents { low: 0, high: 2, max: 3, oom: 1, oom_kill: 1 },
+            },
+            stats
+        );
+        assert!(stats.to_string().starts_with("memory.max:4194304\nmemory.high:max\n"));
+    }
+
+    #[test]
+    fn test_resolve_watermark() {
+        let root = fake_root("max\n", "2000\n");
+        let cg = Cgroup::discover_in(root.path()).expect("discover");
+        assert_eq!(Some(2000), cg.effective_memory_max().expect("effective_memory_max"));
+        assert_eq!(Some(1600), cg.resolve(Watermark::PercentOfLimit(80.0)).expect("resolve"));
+        assert_eq!(Some(10), cg.resolve(Watermark::Bytes(10)).expect("resolve"));
+
+        let root = fake_root("max\n", "max\n");
+        let cg = Cgroup::discover_in(root.path()).expect("discover");
+        assert_eq!(None, cg.resolve(Watermark::PercentOfLimit(80.0)).expect("resolve"));
+        assert_eq!("80.5%", Watermark::PercentOfLimit(80.5).to_string());
+    }
+
+    #[test]
+    fn test_namespace_root() {
+        // A container sees its own cgroup as the root of the hierarchy.
+        let root = tempfile::tempdir().expect("tempdir");
+        write(&root, PROC_SELF_CGROUP, "0::/\n");
+        write(&root, "sys/fs/cgroup/memory.max", "2000\n");
+        write(&root, "sys/fs/cgroup/memory.current", "1000\n");
+        let cg = Cgroup::discover_in(root.path()).expect("discover");
+        assert_eq!(Some(2000), cg.effective_memory_max().expect("effective_memory_max"));
+        assert_eq!(Some(1000), cg.resolve(Watermark::PercentOfLimit(50.0)).expect("resolve"));
+
+        // The host root has no memory.max.
+        fs::remove_file(root.path().join("sys/fs/cgroup/memory.max")).expect("remove_file");
+        assert_eq!(None, cg.effective_memory_max().expect("effective_memory_max"));
+    }
+
+    #[test]
+    fn test_not_found() {
+        let root = tempfile::tempdir().expect("tempdir");
+        fs::create_dir_all(root.path().join("proc/self")).expect("create_dir_all");
+        fs::write(root.path().join(PROC_SELF_CGROUP), "1:memory:/foo\n").expect("write");
+        assert!(matches!(Cgroup::discover_in(root.path()), Err(Error::NotFound)));
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An allocator layer that fails allocations on demand.
+//!
+//! Meant for testing OOM handling, e.g. stacked below
+//! [`crate::oompanic::OomPanic`] and exercised under
+//! [`std::panic::catch_unwind`]. A [`Schedule`] can be set for all threads via
+//! [`Failing::set_schedule`] or for the current thread only via [`scope`],
+//! which takes precedence.
+
+use crate::layer::{Layer, Stacked};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+/// When to fail allocations. All set conditions are checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Schedule {
+    /// Fail the n-th allocation (1-based) after the schedule was set.
+    pub nth: Option<u64>,
+    /// Fail every allocation larger than this many bytes.
+    pub above: Option<usize>,
+    /// Fail allocations with this probability (0.0-1.0).
+    pub probability: Option<f64>,
+    /// Seed of the random number generator used for `probability`.
+    pub seed: u64,
+}
+
+impl Schedule {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { nth: None, above: None, probability: None, seed: 0 }
+    }
+
+    #[must_use]
+    pub const fn fail_nth(mut self, n: u64) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    #[must_use]
+    pub const fn fail_above(mut self, size: usize) -> Self {
+        self.above = Some(size);
+        self
+    }
+
+    #[must_use]
+    pub const fn fail_with_probability(mut self, p: f64, seed: u64) -> Self {
+        self.probability = Some(p);
+        self.seed = seed;
+        self
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss, clippy::cast_sign_loss)]
+    fn threshold(&self) -> u64 {
+        match self.probability {
+            None => 0,
+            Some(p) if p >= 1.0 => u64::MAX,
+            Some(p) => (p.max(0.0) * u64::MAX as f64) as u64,
+        }
+    }
+}
+
+// Per-thread schedule with its own allocation counter and RNG state.
+#[derive(Clone, Copy)]
+struct LocalState {
+    nth: u64,
+    above: usize,
+    threshold: u64,
+    count: u64,
+    rng: u64,
+}
+
+thread_local! {
+    static LOCAL: Cell<Option<LocalState>> = const { Cell::new(None) };
+}
+
+/// Sets the schedule for the current thread, overriding the allocator's.
+#[inline]
+pub fn set_thread_schedule(schedule: Option<Schedule>) {
+    LOCAL.with(|l| {
+        l.set(schedule.map(|s| LocalState {
+            nth: s.nth.unwrap_or(0),
+            above: s.above.unwrap_or(usize::MAX),
+            threshold: s.threshold(),
+            count: 0,
+            rng: s.seed,
+        }));
+    });
+}
+
+/// Runs `f` with `schedule` set for the current thread only.
+pub fn scope<F: FnOnce() -> R, R>(schedule: Schedule, f: F) -> R {
+    struct Reset(Option<LocalState>);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            LOCAL.with(|l| l.set(self.0));
+        }
+    }
+
+    let _reset = Reset(LOCAL.with(Cell::get));
+    set_thread_schedule(Some(schedule));
+    f()
+}
+
+// splitmix64
+fn next_random(state: u64) -> (u64, u64) {
+    let state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (state, z ^ (z >> 31))
+}
+
+/// A global allocator wrapper with a [`Failing`] layer.
+pub type FailingAllocator<T> = Stacked<Failing, T>;
+
+/// An allocator layer failing allocations according to a [`Schedule`].
+pub struct Failing {
+    enabled: AtomicBool,
+    nth: AtomicU64,
+    above: AtomicUsize,
+    threshold: AtomicU64,
+    count: AtomicU64,
+    rng: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl Failing {
+    /// Creates a layer without failing any allocations.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            nth: AtomicU64::new(0),
+            above: AtomicUsize::new(usize::MAX),
+            threshold: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            rng: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets the schedule for all threads without a thread schedule.
+    pub fn set_schedule(&self, schedule: Option<Schedule>) {
+        self.enabled.store(false, Ordering::SeqCst);
+        if let Some(s) = schedule {
+            self.nth.store(s.nth.unwrap_or(0), Ordering::Relaxed);
+            self.above.store(s.above.unwrap_or(usize::MAX), Ordering::Relaxed);
+            self.threshold.store(s.threshold(), Ordering::Relaxed);
+            self.count.store(0, Ordering::Relaxed);
+            self.rng.store(s.seed, Ordering::Relaxed);
+            self.enabled.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the number of allocations failed on purpose.
+    #[inline]
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    fn should_fail(&self, size: usize) -> bool {
+        let fail = LOCAL.with(|l| {
+            l.get().map(|mut state| {
+                state.count += 1;
+                let (rng, random) = next_random(state.rng);
+                state.rng = rng;
+                l.set(Some(state));
+                state.count == state.nth || size > state.above || random < state.threshold
+            })
+        });
+        let fail = fail.unwrap_or_else(|| {
+            if !self.enabled.load(Ordering::Relaxed) {
+                return false;
+            }
+            let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+            let state = self.rng.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+            let (_, random) = next_random(state);
+            count == self.nth.load(Ordering::Relaxed)
+                || size > self.above.load(Ordering::Relaxed)
+                || random < self.threshold.load(Ordering::Relaxed)
+        });
+        if fail {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+}
+
+impl Default for Failing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(unsafe_code)]
+unsafe impl Layer for Failing {
+    #[inline]
+    unsafe fn alloc<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        if self.should_fail(layout.size()) {
+            return ptr::null_mut();
+        }
+        inner.alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        if self.should_fail(layout.size()) {
+            return ptr::null_mut();
+        }
+        inner.alloc_zeroed(layout)
+    }
+
+    #[inline]
+    unsafe fn realloc<A: GlobalAlloc>(
+        &self,
+        inner: &A,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        if new_size > layout.size() && self.should_fail(new_size) {
+            return ptr::null_mut();
+        }
+        inner.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+#[allow(unsafe_code)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    fn try_alloc<T: GlobalAlloc>(alloc: &T, size: usize) -> bool {
+        let layout = Layout::from_size_align(size, 8).expect("layout");
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            if ptr.is_null() {
+                return false;
+            }
+            alloc.dealloc(ptr, layout);
+        }
+        true
+    }
+
+    #[test]
+    fn test_global_schedule() {
+        let alloc = FailingAllocator::new(Failing::new(), System);
+        assert!(try_alloc(&alloc, 8));
+
+        alloc.layer().set_schedule(Some(Schedule::new().fail_nth(3).fail_above(1024)));
+        assert_eq!([true, true, false, true], [8, 8, 8, 8].map(|size| try_alloc(&alloc, size)));
+        assert!(!try_alloc(&alloc, 1025));
+        assert!(try_alloc(&alloc, 1024));
+        assert_eq!(2, alloc.layer().injected());
+
+        alloc.layer().set_schedule(None);
+        assert!(try_alloc(&alloc, 1 << 20));
+    }
+
+    #[test]
+    fn test_probability_is_deterministic() {
+        let alloc = FailingAllocator::new(Failing::new(), System);
+        let run = || {
+            scope(Schedule::new().fail_with_probability(0.5, 42), || {
+                (0..64).map(|_| try_alloc(&alloc, 8)).collect::<Vec<_>>()
+            })
+        };
+        let first = run();
+        assert_eq!(first, run());
+        let failed = first.iter().filter(|ok| !**ok).count();
+        assert!(failed > 8 && failed < 56, "{failed}");
+
+        assert!(scope(Schedule::new().fail_with_probability(1.0, 0), || !try_alloc(&alloc, 8)));
+        assert!(scope(Schedule::new().fail_with_probability(0.0, 0), || try_alloc(&alloc, 8)));
+    }
+
+    #[test]
+    fn test_scope() {
+        let alloc = FailingAllocator::new(Failing::new(), System);
+        alloc.layer().set_schedule(Some(Schedule::new().fail_above(16)));
+        scope(Schedule::new().fail_nth(1), || {
+            assert!(!try_alloc(&alloc, 8));
+            assert!(try_alloc(&alloc, 32));
+        });
+        assert!(!try_alloc(&alloc, 32));
+        std::thread::scope(|s| {
+            s.spawn(|| scope(Schedule::new(), || assert!(try_alloc(&alloc, 32))));
+        });
+    }
+
+    #[cfg(feature = "jemalloc-profiling")]
+    #[test]
+    fn test_with_oompanic() {
+        use crate::oompanic::{AllocFailure, AllocKind, OomPanic};
+        use std::panic;
+
+        let _serial = crate::oompanic::tests::serial();
+        let alloc = FailingAllocator::new(Failing::new(), System).with(OomPanic);
+        let err = scope(Schedule::new().fail_above(100), || {
+            panic::catch_unwind(|| try_alloc(&alloc, 128)).expect_err("must panic")
+        });
+        let failure = err.downcast_ref::<AllocFailure>().expect("AllocFailure payload");
+        assert_eq!(AllocFailure { size: 128, align: 8, kind: AllocKind::Alloc }, *failure);
+        assert!(try_alloc(&alloc, 128));
+    }
+}
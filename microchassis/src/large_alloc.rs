@@ -0,0 +1,404 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An allocator layer logging large allocations with a backtrace.
+//!
+//! Heap profiling samples allocations, so a rare giant `Vec` growth is easily
+//! missed. [`LargeAllocLogger`] emits a `tracing` warning with the size and
+//! the symbolized top frames for every allocation or growing reallocation at
+//! or above a threshold, at most once per interval and call site. The call
+//! site is the first frame outside the allocator, e.g. the code pushing to a
+//! `Vec`, regardless of its callers. Frames are classified once per address,
+//! so rate-limited allocations are not symbolized.
+
+use crate::{
+    layer::{self, Layer},
+    oompanic::AllocKind,
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ffi::c_void,
+    fmt::Write,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use lazy_static::lazy_static;
+use std::time::Instant;
+
+const DEFAULT_MAX_FRAMES: usize = 8;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+// Frames captured per allocation.
+const SITE_FRAMES: usize = 24;
+const SITES: usize = 64;
+// Slots searched for a site, starting at its hash.
+const PROBES: usize = 8;
+// Cached frame classifications.
+const FRAMES: usize = 256;
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+}
+
+struct Site {
+    key: AtomicU64,
+    last: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SITE: Site = Site { key: AtomicU64::new(0), last: AtomicU64::new(0) };
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_FRAME: AtomicU64 = AtomicU64::new(0);
+
+/// An allocator layer logging allocations of at least `threshold` bytes.
+pub struct LargeAllocLogger {
+    threshold: AtomicUsize,
+    max_frames: AtomicUsize,
+    interval: AtomicU64,
+    logged: AtomicU64,
+    suppressed: AtomicU64,
+    sites: [Site; SITES],
+    // `ip << 1 | internal`, see `is_internal_ip`.
+    frames: [AtomicU64; FRAMES],
+}
+
+impl LargeAllocLogger {
+    /// Logs allocations of at least `threshold` bytes, once a minute per
+    /// call site, with up to 8 frames.
+    #[must_use]
+    pub const fn new(threshold: usize) -> Self {
+        Self {
+            threshold: AtomicUsize::new(threshold),
+            max_frames: AtomicUsize::new(DEFAULT_MAX_FRAMES),
+            interval: AtomicU64::new(DEFAULT_INTERVAL.as_secs() * 1_000_000_000),
+            logged: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+            sites: [EMPTY_SITE; SITES],
+            frames: [EMPTY_FRAME; FRAMES],
+        }
+    }
+
+    /// Sets the size threshold. `None` disables logging.
+    #[inline]
+    pub fn set_threshold(&self, threshold: Option<usize>) {
+        self.threshold.store(threshold.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn threshold(&self) -> Option<usize> {
+        Some(self.threshold.load(Ordering::Relaxed)).filter(|&t| t != usize::MAX)
+    }
+
+    /// Sets the number of symbolized frames included in an event.
+    #[inline]
+    pub fn set_max_frames(&self, frames: usize) {
+        self.max_frames.store(frames, Ordering::Relaxed);
+    }
+
+    /// Sets the minimum time between two events of the same call site.
+    #[allow(clippy::cast_possible_truncation)]
+    #[inline]
+    pub fn set_interval(&self, interval: Duration) {
+        self.interval.store(interval.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the number of emitted events.
+    #[inline]
+    pub fn logged(&self) -> u64 {
+        self.logged.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of large allocations not logged due to rate limiting.
+    #[inline]
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn check(&self, size: usize, kind: AllocKind) {
+        if size >= self.threshold.load(Ordering::Relaxed) && !layer::is_reentrant() {
+            layer::guarded(|| self.log(size, kind));
+        }
+    }
+
+    #[inline(never)]
+    fn log(&self, size: usize, kind: AllocKind) {
+        let mut ips = [0_usize; SITE_FRAMES];
+        let n = capture(&mut ips);
+        if !self.allow(self.site_key(&ips[..n]), now()) {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.logged.fetch_add(1, Ordering::Relaxed);
+        let frames = symbolize(&ips[..n], self.max_frames.load(Ordering::Relaxed));
+        tracing::warn!(size, %kind, backtrace = %frames, "large allocation");
+    }
+
+    // Rate limits per call site. A site is looked up in up to PROBES slots;
+    // if none is free, the least recently logged one is replaced, which errs
+    // on the side of logging.
+    fn allow(&self, key: u64, now: u64) -> bool {
+        let interval = self.interval.load(Ordering::Relaxed);
+        let start = slot(key);
+        let mut oldest = (u64::MAX, start);
+        for i in 0..PROBES {
+            let index = (start + i) % SITES;
+            let site = &self.sites[index];
+            match site.key.compare_exchange(0, key, Ordering::Relaxed, Ordering::Relaxed) {
+                Err(k) if k == key => {
+                    if now.saturating_sub(site.last.load(Ordering::Relaxed)) < interval {
+                        return false;
+                    }
+                    site.last.store(now, Ordering::Relaxed);
+                    return true;
+                }
+                Err(_) => {
+                    let last = site.last.load(Ordering::Relaxed);
+                    if last < oldest.0 {
+                        oldest = (last, index);
+                    }
+                }
+                Ok(_) => {
+                    site.last.store(now, Ordering::Relaxed);
+                    return true;
+                }
+            }
+        }
+        let site = &self.sites[oldest.1];
+        site.key.store(key, Ordering::Relaxed);
+        site.last.store(now, Ordering::Relaxed);
+        true
+    }
+
+    // The address of the first frame outside the allocator machinery, so the
+    // same allocation is one site regardless of the callers above it. Never
+    // 0, which marks an empty site.
+    fn site_key(&self, ips: &[usize]) -> u64 {
+        let site = ips.iter().copied().find(|&ip| !self.is_internal_ip(ip));
+        site.or_else(|| ips.first().copied()).map_or(1, |ip| ip as u64).max(1)
+    }
+
+    // Classifies a frame by its symbols once, so rate-limited allocations
+    // don't symbolize. Addresses with the top bit set can't be cached and are
+    // resolved every time.
+    fn is_internal_ip(&self, ip: usize) -> bool {
+        let key = ip as u64;
+        let frame = &self.frames[hash(key) % FRAMES];
+        let cached = frame.load(Ordering::Relaxed);
+        if cached >> 1 == key && cached != 0 {
+            return cached & 1 == 1;
+        }
+        let mut internal = false;
+        backtrace::resolve(ip as *mut c_void, |symbol| {
+            // Inlined functions come first, the outermost one decides.
+            internal = symbol.name().map_or(false, |n| is_internal(&n.to_string()));
+        });
+        frame.store(key << 1 | u64::from(internal), Ordering::Relaxed);
+        internal
+    }
+}
+
+// Fibonacci hashing, keys are code addresses.
+#[allow(clippy::cast_possible_truncation)]
+fn hash(key: u64) -> usize {
+    (key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize
+}
+
+fn slot(key: u64) -> usize {
+    hash(key) % SITES
+}
+
+// Monotonic, wall-clock changes don't affect the interval.
+#[allow(clippy::cast_possible_truncation)]
+fn now() -> u64 {
+    START.elapsed().as_nanos() as u64
+}
+
+// Collects return addresses without symbolizing or allocating.
+#[inline(never)]
+fn capture(ips: &mut [usize]) -> usize {
+    let mut n = 0;
+    backtrace::trace(|frame| {
+        ips[n] = frame.ip() as usize;
+        n += 1;
+        n < ips.len()
+    });
+    n
+}
+
+// Frames of the allocator machinery preceding the allocating code.
+fn is_internal(name: &str) -> bool {
+    const PREFIXES: &[&str] = &[
+        "backtrace::",
+        "microchassis::large_alloc::capture",
+        "microchassis::large_alloc::LargeAllocLogger",
+        "microchassis::layer::",
+        "__rust",
+        "__rg_",
+        "alloc::",
+        "<alloc::",
+        "core::",
+        "<core::",
+        "std::alloc::",
+    ];
+    PREFIXES.iter().any(|p| name.starts_with(p))
+        || name.contains(" as microchassis::layer::Layer>")
+        || name.contains(" as core::alloc::global::GlobalAlloc>")
+}
+
+fn symbolize(ips: &[usize], max_frames: usize) -> String {
+    let mut out = String::new();
+    let mut skipping = true;
+    let mut written = 0;
+    for &ip in ips {
+        if written >= max_frames {
+            break;
+        }
+        backtrace::resolve(ip as *mut c_void, |symbol| {
+            let Some(name) = symbol.name().map(|n| n.to_string()) else {
+                return;
+            };
+            if skipping && is_internal(&name) {
+                return;
+            }
+            skipping = false;
+            if written >= max_frames {
+                return;
+            }
+            let _ = write!(out, "\n  {name}");
+            if let (Some(file), Some(line)) = (symbol.filename(), symbol.lineno()) {
+                let _ = write!(out, " at {}:{line}", file.display());
+            }
+            written += 1;
+        });
+    }
+    out
+}
+
+#[allow(unsafe_code)]
+unsafe impl Layer for LargeAllocLogger {
+    #[inline]
+    unsafe fn alloc<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        self.check(layout.size(), AllocKind::Alloc);
+        inner.alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        self.check(layout.size(), AllocKind::AllocZeroed);
+        inner.alloc_zeroed(layout)
+    }
+
+    #[inline]
+    unsafe fn realloc<A: GlobalAlloc>(
+        &self,
+        inner: &A,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        if new_size > layout.size() {
+            self.check(new_size, AllocKind::Realloc);
+        }
+        inner.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+#[allow(unsafe_code)]
+mod tests {
+    use super::*;
+    use crate::layer::Stacked;
+    use std::alloc::System;
+
+    fn alloc_free<T: GlobalAlloc>(alloc: &T, size: usize) {
+        let layout = Layout::from_size_align(size, 8).expect("layout");
+        unsafe { alloc.dealloc(alloc.alloc(layout), layout) };
+    }
+
+    #[inline(never)]
+    fn other_site<T: GlobalAlloc>(alloc: &T, size: usize) {
+        let layout = Layout::from_size_align(size, 8).expect("layout");
+        unsafe { alloc.dealloc(alloc.alloc(layout), layout) };
+    }
+
+    #[inline(never)]
+    fn other_caller<T: GlobalAlloc>(alloc: &T, size: usize) {
+        alloc_free(alloc, size);
+    }
+
+    #[test]
+    fn test_rate_limit_per_site() {
+        let alloc = Stacked::new(LargeAllocLogger::new(1 << 20), System);
+        for _ in 0..3 {
+            alloc_free(&alloc, 2 << 20);
+        }
+        alloc_free(&alloc, 1024);
+        assert_eq!((1, 2), (alloc.layer().logged(), alloc.layer().suppressed()));
+
+        other_site(&alloc, 1 << 20);
+        assert_eq!(2, alloc.layer().logged());
+        other_caller(&alloc, 1 << 20);
+        assert_eq!((2, 3), (alloc.layer().logged(), alloc.layer().suppressed()));
+
+        alloc.layer().set_interval(Duration::ZERO);
+        alloc_free(&alloc, 2 << 20);
+        assert_eq!(3, alloc.layer().logged());
+
+        alloc.layer().set_threshold(None);
+        other_site(&alloc, 2 << 20);
+        assert_eq!((3, 3), (alloc.layer().logged(), alloc.layer().suppressed()));
+    }
+
+    #[test]
+    fn test_colliding_sites() {
+        let logger = LargeAllocLogger::new(0);
+        let keys: Vec<u64> = (1..).filter(|&k| slot(k) == slot(1)).take(PROBES + 1).collect();
+        for &key in &keys[..PROBES] {
+            assert!(logger.allow(key, 1));
+        }
+        for &key in &keys[..PROBES] {
+            assert!(!logger.allow(key, 2), "{key}");
+        }
+        // Replaces the least recently logged site.
+        assert!(logger.allow(keys[PROBES], 3));
+        assert!(!logger.allow(keys[PROBES], 4));
+        assert!(logger.allow(keys[0], 5));
+    }
+
+    #[test]
+    fn test_frame_cache() {
+        let logger = LargeAllocLogger::new(0);
+        let mut ips = [0_usize; SITE_FRAMES];
+        let n = capture(&mut ips);
+        let key = logger.site_key(&ips[..n]);
+        assert_ne!(ips[0] as u64, key);
+        assert_eq!(key, logger.site_key(&ips[..n]));
+
+        assert!(logger.is_internal_ip(ips[0]));
+        let frame = &logger.frames[hash(ips[0] as u64) % FRAMES];
+        assert_eq!((ips[0] as u64) << 1 | 1, frame.load(Ordering::Relaxed));
+        // Answered from the cache.
+        frame.store((ips[0] as u64) << 1, Ordering::Relaxed);
+        assert!(!logger.is_internal_ip(ips[0]));
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let mut ips = [0_usize; SITE_FRAMES];
+        let n = capture(&mut ips);
+        let frames = symbolize(&ips[..n], 2);
+        assert_eq!(2, frames.lines().filter(|l| !l.is_empty()).count(), "{frames}");
+        assert!(frames.contains("test_symbolize"), "{frames}");
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Composable global allocator middleware.
+//!
+//! A [`Layer`] wraps the calls to an inner [`GlobalAlloc`]. [`Stacked`]
+//! combines a layer with an inner allocator, which can itself be stacked:
+//!
+//! ```ignore
+//! use microchassis::{budget::Budget, layer::Stacked, oompanic::OomPanic};
+//!
+//! #[global_allocator]
+//! static ALLOC: Stacked<OomPanic, Stacked<Budget, tikv_jemallocator::Jemalloc>> =
+//!     Stacked::new(Budget::new(), tikv_jemallocator::Jemalloc).with(OomPanic);
+//! ```
+//!
+//! Disable the `set-jemalloc-global` feature to install a custom stack.
+//!
+//! Layers that run code which may allocate (callbacks, logging, panicking)
+//! do so inside [`guarded`] or while holding [`enter`]'s guard, so nested
+//! allocations can be recognized with [`is_reentrant`] and don't recurse.
+//! The guard is shared by all layers on a thread.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Allocator middleware. All methods default to calling `inner` directly.
+///
+/// # Safety
+///
+/// Implementations must uphold the contract of [`GlobalAlloc`]. Memory must
+/// be allocated and deallocated through `inner`.
+#[allow(unsafe_code)]
+pub unsafe trait Layer {
+    /// Calls [`GlobalAlloc::alloc`] on `inner`.
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::alloc`].
+    #[inline]
+    unsafe fn alloc<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        inner.alloc(layout)
+    }
+
+    /// Calls [`GlobalAlloc::alloc_zeroed`] on `inner`.
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::alloc_zeroed`].
+    #[inline]
+    unsafe fn alloc_zeroed<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        inner.alloc_zeroed(layout)
+    }
+
+    /// Calls [`GlobalAlloc::realloc`] on `inner`.
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    #[inline]
+    unsafe fn realloc<A: GlobalAlloc>(
+        &self,
+        inner: &A,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        inner.realloc(ptr, layout, new_size)
+    }
+
+    /// Calls [`GlobalAlloc::dealloc`] on `inner`.
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::dealloc`].
+    #[inline]
+    unsafe fn dealloc<A: GlobalAlloc>(&self, inner: &A, ptr: *mut u8, layout: Layout) {
+        inner.dealloc(ptr, layout);
+    }
+}
+
+/// A [`Layer`] on top of an inner allocator.
+pub struct Stacked<L: Layer, A: GlobalAlloc> {
+    layer: L,
+    inner: A,
+}
+
+impl<L: Layer, A: GlobalAlloc> Stacked<L, A> {
+    pub const fn new(layer: L, inner: A) -> Self {
+        Self { layer, inner }
+    }
+
+    /// Puts `layer` on top of this stack.
+    pub const fn with<M: Layer>(self, layer: M) -> Stacked<M, Self> {
+        Stacked::new(layer, self)
+    }
+
+    #[inline]
+    pub const fn layer(&self) -> &L {
+        &self.layer
+    }
+
+    #[inline]
+    pub const fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+#[allow(unsafe_code)]
+unsafe impl<L: Layer, A: GlobalAlloc> GlobalAlloc for Stacked<L, A> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.layer.alloc(&self.inner, layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.layer.alloc_zeroed(&self.inner, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.layer.realloc(&self.inner, ptr, layout, new_size)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.layer.dealloc(&self.inner, ptr, layout);
+    }
+}
+
+thread_local! {
+    static REENTRANT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returns true while the current thread runs code inside [`guarded`].
+#[inline]
+pub fn is_reentrant() -> bool {
+    REENTRANT.with(Cell::get)
+}
+
+/// Sets the reentrancy guard for the current thread until the returned
+/// value is dropped, also when unwinding.
+#[must_use]
+#[inline]
+pub fn enter() -> Guard {
+    Guard(REENTRANT.with(|v| v.replace(true)))
+}
+
+/// Restores the previous reentrancy state on drop. See [`enter`].
+pub struct Guard(bool);
+
+impl Drop for Guard {
+    #[inline]
+    fn drop(&mut self) {
+        REENTRANT.with(|v| v.set(self.0));
+    }
+}
+
+/// Runs `f` with the reentrancy guard set for the current thread.
+#[inline]
+pub fn guarded<F: FnOnce() -> R, R>(f: F) -> R {
+    let _guard = enter();
+    f()
+}
+
+/// A layer counting allocations and bytes passing through it.
+#[derive(Debug, Default)]
+pub struct Counting {
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+    allocated: AtomicU64,
+    deallocated: AtomicU64,
+}
+
+/// Snapshot of [`Counting`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub allocated: u64,
+    pub deallocated: u64,
+}
+
+impl Counting {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            allocations: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+            allocated: AtomicU64::new(0),
+            deallocated: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn counts(&self) -> Counts {
+        Counts {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            deallocated: self.deallocated.load(Ordering::Relaxed),
+        }
+    }
+
+    #[inline]
+    fn count_alloc(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.allocated.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn count_dealloc(&self, size: usize) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.deallocated.fetch_add(size as u64, Ordering::Relaxed);
+    }
+}
+
+#[allow(unsafe_code)]
+unsafe impl Layer for Counting {
+    #[inline]
+    unsafe fn alloc<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        let ptr = inner.alloc(layout);
+        if !ptr.is_null() {
+            self.count_alloc(layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        let ptr = inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            self.count_alloc(layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn realloc<A: GlobalAlloc>(
+        &self,
+        inner: &A,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let new_ptr = inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.count_dealloc(layout.size());
+            self.count_alloc(new_size);
+        }
+        new_ptr
+    }
+
+    #[inline]
+    unsafe fn dealloc<A: GlobalAlloc>(&self, inner: &A, ptr: *mut u8, layout: Layout) {
+        inner.dealloc(ptr, layout);
+        self.count_dealloc(layout.size());
+    }
+}
+
+#[cfg(test)]
+#[allow(unsafe_code)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    #[test]
+    fn test_stacked_counting() {
+        let alloc = Stacked::new(Counting::new(), System).with(Counting::new());
+        let layout = Layout::from_size_align(100, 8).expect("layout");
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            let ptr = alloc.realloc(ptr, layout, 200);
+            alloc.dealloc(ptr, Layout::from_size_align(200, 8).expect("layout"));
+        }
+        let expected =
+            Counts { allocations: 2, deallocations: 2, allocated: 300, deallocated: 300 };
+        assert_eq!(expected, alloc.layer().counts());
+        assert_eq!(expected, alloc.inner().layer().counts());
+    }
+
+    #[test]
+    fn test_guarded() {
+        assert!(!is_reentrant());
+        let nested = guarded(|| (is_reentrant(), guarded(is_reentrant), is_reentrant()));
+        assert_eq!((true, true, true), nested);
+        assert!(!is_reentrant());
+        assert!(std::panic::catch_unwind(|| guarded(|| std::panic::resume_unwind(Box::new(()))))
+            .is_err());
+        assert!(!is_reentrant());
+    }
+}
@@ -40,15 +40,37 @@
 )]
 #![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "admin-server")]
+pub mod admin;
+#[cfg(feature = "std")]
+pub mod budget;
+#[cfg(feature = "std")]
+pub mod cgroup;
 pub mod error;
+#[cfg(feature = "std")]
+pub mod failing;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod large_alloc;
+#[cfg(feature = "std")]
+pub mod layer;
+pub mod malloc_conf;
 #[cfg(feature = "jemalloc-profiling")]
 pub mod oompanic;
+#[cfg(feature = "otel")]
+pub mod otel;
 pub mod profiling;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod psi;
+#[cfg(feature = "std")]
+pub mod testing;
+
+#[cfg(test)]
+malloc_conf!("prof:true,prof_active:false,lg_prof_sample:10");
 
 #[cfg(all(feature = "set-jemalloc-global", feature = "oompanic-allocator"))]
 #[global_allocator]
-static ALLOC: crate::oompanic::Allocator<tikv_jemallocator::Jemalloc> =
-    crate::oompanic::Allocator(tikv_jemallocator::Jemalloc);
+static ALLOC: layer::Stacked<oompanic::OomPanic, tikv_jemallocator::Jemalloc> =
+    layer::Stacked::new(oompanic::OomPanic, tikv_jemallocator::Jemalloc);
 
 #[cfg(all(feature = "set-jemalloc-global", not(feature = "oompanic-allocator")))]
 #[global_allocator]
@@ -0,0 +1,87 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compile-time jemalloc options.
+//!
+//! jemalloc reads its options from the `malloc_conf` symbol before `main()`
+//! runs, then from the `MALLOC_CONF` environment variable, which takes
+//! precedence. [`malloc_conf!`](crate::malloc_conf!) defines that symbol:
+//!
+//! ```ignore
+//! microchassis::malloc_conf!("prof:true,prof_active:false,lg_prof_sample:19");
+//! ```
+//!
+//! jemalloc is built with the `_rjem_` prefix, so the symbol is
+//! `_rjem_malloc_conf` and the environment variable `_RJEM_MALLOC_CONF`.
+//! Only one crate in a binary may use the macro.
+
+use core::ffi::c_char;
+
+/// A NUL-terminated options string in the layout of jemalloc's
+/// `const char *malloc_conf`.
+#[repr(transparent)]
+pub struct MallocConf(*const c_char);
+
+// SAFETY: points to an immutable 'static string.
+#[allow(unsafe_code)]
+unsafe impl Sync for MallocConf {}
+
+impl MallocConf {
+    /// Panics at compile time if `conf` is not NUL-terminated.
+    #[must_use]
+    pub const fn new(conf: &'static str) -> Self {
+        let bytes = conf.as_bytes();
+        assert!(!bytes.is_empty() && bytes[bytes.len() - 1] == 0, "malloc_conf: missing NUL");
+        Self(bytes.as_ptr().cast())
+    }
+
+    /// Returns the options without the trailing NUL.
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        // SAFETY: constructed from a 'static NUL-terminated str.
+        #[allow(unsafe_code)]
+        let conf = unsafe { core::ffi::CStr::from_ptr(self.0) };
+        conf.to_str().unwrap_or_default()
+    }
+}
+
+/// Embeds jemalloc options into the binary, e.g.
+/// `malloc_conf!("prof:true,prof_active:false")`.
+/// See the [module documentation](crate::malloc_conf).
+#[macro_export]
+macro_rules! malloc_conf {
+    ($conf:expr) => {
+        #[allow(unsafe_code)]
+        #[export_name = "_rjem_malloc_conf"]
+        #[used]
+        static MALLOC_CONF: $crate::malloc_conf::MallocConf =
+            $crate::malloc_conf::MallocConf::new(concat!($conf, "\0"));
+    };
+}
+
+#[cfg(test)]
+#[allow(unsafe_code)]
+mod tests {
+    use core::ffi::CStr;
+
+    #[test]
+    fn test_malloc_conf() {
+        assert_eq!("prof:true,prof_active:false,lg_prof_sample:10", crate::MALLOC_CONF.as_str());
+        // SAFETY: defined by malloc_conf! in lib.rs.
+        let conf = unsafe { tikv_jemalloc_sys::malloc_conf }.expect("malloc_conf symbol");
+        // SAFETY: see above.
+        let conf = unsafe { CStr::from_ptr(conf) };
+        assert_eq!(Ok(crate::MALLOC_CONF.as_str()), conf.to_str());
+    }
+}
@@ -12,53 +12,371 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::layer::{self, Layer};
 use core::{
     alloc::{GlobalAlloc, Layout},
     cell::Cell,
+    fmt, mem, ptr,
+    sync::atomic::{AtomicI32, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
 };
+use std::{os::fd::RawFd, panic, thread};
 
 /// A global allocator wrapper that does what the oom=panic setting
 /// hopefully soon will do.
+///
+/// The panic payload is an [`AllocFailure`] which can be downcast from
+/// the error returned by [`std::panic::catch_unwind`].
 pub struct Allocator<T: GlobalAlloc>(pub T);
 
+/// The [`Layer`] behind [`Allocator`], for use in a [`layer::Stacked`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OomPanic;
+
+/// The allocator method that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AllocKind {
+    Alloc,
+    AllocZeroed,
+    Realloc,
+}
+
+impl fmt::Display for AllocKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Alloc => "alloc",
+            Self::AllocZeroed => "alloc_zeroed",
+            Self::Realloc => "realloc",
+        })
+    }
+}
+
+/// Panic payload of a failed allocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocFailure {
+    /// Requested size. For `realloc` this is the new size.
+    pub size: usize,
+    pub align: usize,
+    pub kind: AllocKind,
+}
+
+impl fmt::Display for AllocFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "memory allocation failed: {} of {} bytes (align {})",
+            self.kind, self.size, self.align
+        )
+    }
+}
+
+/// Number of failed allocations that caused a panic, by [`AllocKind`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Failures {
+    pub alloc: u64,
+    pub alloc_zeroed: u64,
+    pub realloc: u64,
+}
+
+impl Failures {
+    #[must_use]
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.alloc + self.alloc_zeroed + self.realloc
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn get(&self, kind: AllocKind) -> u64 {
+        match kind {
+            AllocKind::Alloc => self.alloc,
+            AllocKind::AllocZeroed => self.alloc_zeroed,
+            AllocKind::Realloc => self.realloc,
+        }
+    }
+}
+
 thread_local! {
     static PANICKING: Cell<bool> = const { Cell::new(false) };
 }
 
+static FAILURES_ALLOC: AtomicU64 = AtomicU64::new(0);
+static FAILURES_ALLOC_ZEROED: AtomicU64 = AtomicU64::new(0);
+static FAILURES_REALLOC: AtomicU64 = AtomicU64::new(0);
+
+/// Pre-allocated emergency block, freed on the first failing allocation.
+/// The block starts with its own size.
+static RESERVE: AtomicPtr<usize> = AtomicPtr::new(ptr::null_mut());
+/// Sizes of the published blocks. A block is counted before it is published
+/// and uncounted after it is taken out, so races don't leave a stale size.
+static RESERVE_SIZE: AtomicUsize = AtomicUsize::new(0);
+static RESERVE_CONSUMED: AtomicU64 = AtomicU64::new(0);
+
+fn reserve_layout(size: usize) -> Option<Layout> {
+    Layout::from_size_align(size.max(mem::size_of::<usize>()), mem::align_of::<usize>()).ok()
+}
+
+/// Allocates an emergency reserve of `size` bytes from `inner`, replacing
+/// any previous reserve. A `size` of zero drops the reserve.
+///
+/// When an allocation fails the reserve is freed back to the inner allocator
+/// and the allocation is retried, giving the panic path and error reporting
+/// some room to complete. Returns false if the reserve could not be
+/// allocated.
+///
+/// ```ignore
+/// unsafe { oompanic::set_reserve(ALLOC.inner(), 1 << 20) };
+/// ```
+///
+/// # Safety
+///
+/// `inner` must be the allocator below every [`OomPanic`] layer and
+/// [`Allocator`] in the process, which free the reserve through their own.
+#[allow(unsafe_code, clippy::cast_ptr_alignment)]
+pub unsafe fn set_reserve<A: GlobalAlloc>(inner: &A, size: usize) -> bool {
+    let new = if size == 0 {
+        ptr::null_mut()
+    } else {
+        let Some(layout) = reserve_layout(size) else {
+            return false;
+        };
+        // SAFETY: layout has non-zero size.
+        let ptr = inner.alloc(layout).cast::<usize>();
+        if ptr.is_null() {
+            return false;
+        }
+        // SAFETY: layout is aligned for and large enough for one usize.
+        ptr.write(layout.size());
+        RESERVE_SIZE.fetch_add(layout.size(), Ordering::Relaxed);
+        ptr
+    };
+    free_reserve(inner, RESERVE.swap(new, Ordering::AcqRel));
+    true
+}
+
+/// Returns the size of the currently held emergency reserve.
+#[inline]
+pub fn reserve_size() -> usize {
+    RESERVE_SIZE.load(Ordering::Relaxed)
+}
+
+/// Returns how many times the emergency reserve was released due to a
+/// failed allocation.
+#[inline]
+pub fn reserve_consumed() -> u64 {
+    RESERVE_CONSUMED.load(Ordering::Relaxed)
+}
+
+fn release_reserve<A: GlobalAlloc>(inner: &A) -> bool {
+    let ptr = RESERVE.swap(ptr::null_mut(), Ordering::AcqRel);
+    if ptr.is_null() {
+        return false;
+    }
+    free_reserve(inner, ptr);
+    RESERVE_CONSUMED.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+#[allow(unsafe_code)]
+fn free_reserve<A: GlobalAlloc>(inner: &A, ptr: *mut usize) {
+    if ptr.is_null() {
+        return;
+    }
+    // SAFETY: ptr was allocated from inner by set_reserve and taken out of
+    // RESERVE.
+    unsafe {
+        let size = ptr.read();
+        inner
+            .dealloc(ptr.cast(), Layout::from_size_align_unchecked(size, mem::align_of::<usize>()));
+        RESERVE_SIZE.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of failed allocations that caused a panic.
+#[inline]
+pub fn failures() -> Failures {
+    Failures {
+        alloc: FAILURES_ALLOC.load(Ordering::Relaxed),
+        alloc_zeroed: FAILURES_ALLOC_ZEROED.load(Ordering::Relaxed),
+        realloc: FAILURES_REALLOC.load(Ordering::Relaxed),
+    }
+}
+
+// Don't panic again while the panic of a previous failure is in progress.
+// Once unwinding is done (e.g. caught by `catch_unwind`) panic again.
+#[inline]
+fn should_panic() -> bool {
+    !PANICKING.with(Cell::get) || !thread::panicking()
+}
+
+static REPORT_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Sets the file descriptor a report is written to before panicking on a
+/// failed allocation, e.g. `Some(libc::STDERR_FILENO)`. `None` disables it.
+///
+/// The report contains the failed layout, `stats.allocated`, `stats.resident`
+/// and the thread name. It is formatted on the stack and written with
+/// `write(2)`, so it does not need the heap.
+#[inline]
+pub fn set_report_fd(fd: Option<RawFd>) {
+    REPORT_FD.store(fd.unwrap_or(-1), Ordering::Relaxed);
+}
+
+/// Fixed-capacity formatting buffer. Output beyond capacity is dropped.
+struct StackBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> StackBuf<N> {
+    const fn new() -> Self {
+        Self { buf: [0; N], len: 0 }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> fmt::Write for StackBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(N - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn report(failure: &AllocFailure) {
+    use fmt::Write as _;
+
+    let fd = REPORT_FD.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+
+    let mut name = [0_u8; 64];
+    let name = thread_name(&mut name);
+
+    let mut out = StackBuf::<512>::new();
+    let _ = write!(out, "oompanic: {failure} in thread '{name}'");
+    if crate::profiling::mallctl::advance_epoch().is_ok() {
+        if let Ok(stats) = crate::profiling::mallctl::global_stats() {
+            let _ = write!(
+                out,
+                "; stats.allocated={} stats.resident={}",
+                stats.allocated, stats.resident
+            );
+        }
+    }
+    let _ = out.write_str("\n");
+
+    write_all(fd, out.as_bytes());
+}
+
+#[allow(unsafe_code)]
+fn thread_name(buf: &mut [u8; 64]) -> &str {
+    // SAFETY: buf is valid for its length and gets NUL-terminated.
+    let ret = unsafe {
+        libc::pthread_getname_np(libc::pthread_self(), buf.as_mut_ptr().cast(), buf.len())
+    };
+    if ret != 0 {
+        return "<unknown>";
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    core::str::from_utf8(&buf[..len]).unwrap_or("<invalid>")
+}
+
+#[allow(unsafe_code)]
+fn write_all(fd: RawFd, mut buf: &[u8]) {
+    while !buf.is_empty() {
+        // SAFETY: buf is valid for its length.
+        let ret = unsafe { libc::write(fd, buf.as_ptr().cast(), buf.len()) };
+        match usize::try_from(ret) {
+            Ok(0) => return,
+            Ok(n) => buf = &buf[n..],
+            Err(_) if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {
+            }
+            Err(_) => return,
+        }
+    }
+}
+
 #[allow(clippy::panic)]
 #[inline]
-fn panic_alloc() -> ! {
+fn panic_alloc(layout: Layout, size: usize, kind: AllocKind) -> ! {
+    match kind {
+        AllocKind::Alloc => &FAILURES_ALLOC,
+        AllocKind::AllocZeroed => &FAILURES_ALLOC_ZEROED,
+        AllocKind::Realloc => &FAILURES_REALLOC,
+    }
+    .fetch_add(1, Ordering::Relaxed);
+    // Stays set while unwinding, unlike the layer guard.
     PANICKING.with(|v| v.set(true));
-    panic!("memory allocation failed");
+    let failure = AllocFailure { size, align: layout.align(), kind };
+    layer::guarded(|| report(&failure));
+    panic::panic_any(failure);
 }
 
 #[allow(unsafe_code)]
-unsafe impl<T: GlobalAlloc> GlobalAlloc for Allocator<T> {
+unsafe impl Layer for OomPanic {
     #[inline]
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let ptr = self.0.alloc(layout);
-        if ptr.is_null() && !PANICKING.with(Cell::get) {
-            panic_alloc();
+    unsafe fn alloc<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        let mut ptr = inner.alloc(layout);
+        if ptr.is_null() && release_reserve(inner) {
+            ptr = inner.alloc(layout);
+        }
+        if ptr.is_null() && should_panic() {
+            panic_alloc(layout, layout.size(), AllocKind::Alloc);
         }
         ptr
     }
 
     #[inline]
-    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        let ptr = self.0.alloc_zeroed(layout);
-        if ptr.is_null() && !PANICKING.with(Cell::get) {
-            panic_alloc();
+    unsafe fn alloc_zeroed<A: GlobalAlloc>(&self, inner: &A, layout: Layout) -> *mut u8 {
+        let mut ptr = inner.alloc_zeroed(layout);
+        if ptr.is_null() && release_reserve(inner) {
+            ptr = inner.alloc_zeroed(layout);
+        }
+        if ptr.is_null() && should_panic() {
+            panic_alloc(layout, layout.size(), AllocKind::AllocZeroed);
         }
         ptr
     }
 
     #[inline]
-    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        let ptr = self.0.realloc(ptr, layout, new_size);
-        if ptr.is_null() && !PANICKING.with(Cell::get) && new_size > layout.size() {
-            panic_alloc();
+    unsafe fn realloc<A: GlobalAlloc>(
+        &self,
+        inner: &A,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let mut new_ptr = inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() && release_reserve(inner) {
+            new_ptr = inner.realloc(ptr, layout, new_size);
         }
-        ptr
+        if new_ptr.is_null() && should_panic() && new_size > layout.size() {
+            panic_alloc(layout, new_size, AllocKind::Realloc);
+        }
+        new_ptr
+    }
+}
+
+#[allow(unsafe_code)]
+unsafe impl<T: GlobalAlloc> GlobalAlloc for Allocator<T> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        OomPanic.alloc(&self.0, layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        OomPanic.alloc_zeroed(&self.0, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        OomPanic.realloc(&self.0, ptr, layout, new_size)
     }
 
     #[inline]
@@ -66,3 +384,172 @@ unsafe impl<T: GlobalAlloc> GlobalAlloc for Allocator<T> {
         self.0.dealloc(ptr, layout);
     }
 }
+
+#[cfg(test)]
+#[allow(unsafe_code)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{
+        alloc::System,
+        panic, ptr,
+        sync::{Mutex, MutexGuard, PoisonError},
+    };
+
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    /// Held by tests with failing allocations, which would release the
+    /// reserve of another test.
+    pub fn serial() -> MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    struct Exhausted;
+
+    unsafe impl GlobalAlloc for Exhausted {
+        unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+            ptr::null_mut()
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    }
+
+    #[test]
+    fn test_alloc_failure_payload() {
+        let _serial = serial();
+        let alloc = Allocator(Exhausted);
+        let layout = Layout::from_size_align(1024, 16).expect("layout");
+        let before = failures();
+
+        for kind in [AllocKind::Alloc, AllocKind::AllocZeroed, AllocKind::Realloc] {
+            let err = panic::catch_unwind(|| unsafe {
+                match kind {
+                    AllocKind::Alloc => alloc.alloc(layout),
+                    AllocKind::AllocZeroed => alloc.alloc_zeroed(layout),
+                    // Exhausted never touches the old block.
+                    AllocKind::Realloc => {
+                        alloc.realloc(ptr::NonNull::dangling().as_ptr(), layout, 2048)
+                    }
+                }
+            })
+            .expect_err("allocation must panic");
+            let failure = err.downcast_ref::<AllocFailure>().expect("AllocFailure payload");
+            let size = if kind == AllocKind::Realloc { 2048 } else { 1024 };
+            assert_eq!(&AllocFailure { size, align: 16, kind }, failure);
+        }
+
+        let after = failures();
+        assert!(after.alloc > before.alloc);
+        assert!(after.alloc_zeroed > before.alloc_zeroed);
+        assert!(after.realloc > before.realloc);
+        assert_eq!(after.realloc, after.get(AllocKind::Realloc));
+    }
+
+    struct AllocOnDrop<'a> {
+        alloc: &'a Allocator<Exhausted>,
+        failed: &'a Cell<bool>,
+    }
+
+    impl Drop for AllocOnDrop<'_> {
+        fn drop(&mut self) {
+            let layout = Layout::from_size_align(8, 8).expect("layout");
+            self.failed.set(unsafe { self.alloc.alloc(layout) }.is_null());
+        }
+    }
+
+    #[test]
+    fn test_alloc_failure_while_unwinding() {
+        let _serial = serial();
+        let alloc = Allocator(Exhausted);
+        let layout = Layout::from_size_align(64, 8).expect("layout");
+        let failed = Cell::new(false);
+
+        let err = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _on_drop = AllocOnDrop { alloc: &alloc, failed: &failed };
+            unsafe { alloc.alloc(layout) }
+        }))
+        .expect_err("allocation must panic");
+        assert!(err.is::<AllocFailure>());
+        // A second panic would have aborted.
+        assert!(failed.get());
+
+        // Panics again once unwinding is done.
+        assert!(panic::catch_unwind(|| unsafe { alloc.alloc(layout) }).is_err());
+    }
+
+    /// Fails allocations exceeding the remaining bytes.
+    struct Tight(AtomicUsize);
+
+    unsafe impl GlobalAlloc for Tight {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let remaining = self.0.load(Ordering::Relaxed);
+            if layout.size() > remaining {
+                return ptr::null_mut();
+            }
+            self.0.store(remaining - layout.size(), Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.0.fetch_add(layout.size(), Ordering::Relaxed);
+            System.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_reserve_released_on_failure() {
+        let _serial = serial();
+        let alloc = Allocator(Tight(AtomicUsize::new(1 << 20)));
+        let layout = Layout::from_size_align(64, 8).expect("layout");
+        let before = reserve_consumed();
+
+        assert!(unsafe { set_reserve(&alloc.0, 1 << 20) });
+        assert_eq!(1 << 20, reserve_size());
+        // The retry gets the memory of the reserve.
+        let ptr = unsafe { alloc.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!((0, before + 1), (reserve_size(), reserve_consumed()));
+        unsafe { alloc.dealloc(ptr, layout) };
+
+        assert!(unsafe { set_reserve(&alloc.0, 1 << 19) });
+        assert!(unsafe { set_reserve(&alloc.0, 1 << 18) });
+        assert_eq!(
+            (1 << 18, (1 << 20) - (1 << 18)),
+            (reserve_size(), alloc.0 .0.load(Ordering::Relaxed))
+        );
+        assert!(unsafe { set_reserve(&alloc.0, 0) });
+        assert_eq!((0, 1 << 20), (reserve_size(), alloc.0 .0.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn test_report_fd() {
+        use std::{
+            io::{Read as _, Seek as _},
+            os::fd::AsRawFd as _,
+        };
+
+        let _serial = serial();
+
+        let mut f = tempfile::tempfile().expect("tempfile");
+        let alloc = Allocator(Exhausted);
+        let layout = Layout::from_size_align(4096, 32).expect("layout");
+
+        set_report_fd(Some(f.as_raw_fd()));
+        let result = thread::Builder::new()
+            .name("oom-reporter".to_owned())
+            .spawn(move || panic::catch_unwind(|| unsafe { alloc.alloc(layout) }).is_err())
+            .expect("spawn")
+            .join();
+        set_report_fd(None);
+        assert!(result.expect("join"));
+
+        let mut report = String::new();
+        f.rewind().expect("rewind");
+        f.read_to_string(&mut report).expect("read report");
+        assert!(
+            report.contains("memory allocation failed: alloc of 4096 bytes (align 32)"),
+            "{report}"
+        );
+        assert!(report.contains("in thread 'oom-reporter'"), "{report}");
+        assert!(report.contains("stats.allocated="), "{report}");
+    }
+}
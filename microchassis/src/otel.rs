@@ -0,0 +1,215 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exports allocator and profiling metrics via OpenTelemetry observable instruments.
+
+use crate::{
+    oompanic::{self, AllocKind},
+    profiling::mallctl,
+};
+use opentelemetry::{metrics::Meter, KeyValue};
+
+/// Registers observable instruments for jemalloc stats, profiling state,
+/// profile dumps and oompanic failures with `meter`.
+///
+/// All values are read on collection. `jemalloc.memory` advances the
+/// jemalloc epoch; arena counters use the values cached by that.
+pub fn register(meter: &Meter) {
+    meter
+        .u64_observable_gauge("jemalloc.memory")
+        .with_description("Memory statistics of the jemalloc allocator.")
+        .with_unit("By")
+        .with_callback(|observer| {
+            if mallctl::advance_epoch().is_err() {
+                return;
+            }
+            let Ok(stats) = mallctl::global_stats() else {
+                return;
+            };
+            for (state, value) in [
+                ("allocated", stats.allocated),
+                ("active", stats.active),
+                ("resident", stats.resident),
+                ("mapped", stats.mapped),
+                ("retained", stats.retained),
+                ("metadata", stats.metadata),
+            ] {
+                observer.observe(value as u64, &[KeyValue::new("state", state)]);
+            }
+        })
+        .build();
+
+    register_arena_counter(
+        meter,
+        "jemalloc.arena.allocations",
+        "Number of allocations served by an arena.",
+        |c| c.nmalloc,
+    );
+    register_arena_counter(
+        meter,
+        "jemalloc.arena.deallocations",
+        "Number of deallocations served by an arena.",
+        |c| c.ndalloc,
+    );
+    register_arena_counter(
+        meter,
+        "jemalloc.arena.requests",
+        "Number of allocation requests served by an arena.",
+        |c| c.nrequests,
+    );
+
+    meter
+        .u64_observable_gauge("jemalloc.prof.enabled")
+        .with_description("Whether heap profiling is enabled (opt.prof).")
+        .with_callback(|observer| {
+            observer.observe(u64::from(mallctl::enabled().unwrap_or(false)), &[]);
+        })
+        .build();
+
+    meter
+        .u64_observable_gauge("jemalloc.prof.active")
+        .with_description("Whether heap sampling is active (prof.active).")
+        .with_callback(|observer| {
+            observer.observe(u64::from(mallctl::active().unwrap_or(false)), &[]);
+        })
+        .build();
+
+    meter
+        .u64_observable_gauge("jemalloc.prof.lg_sample")
+        .with_description("Heap sampling interval as log2 of bytes (prof.lg_sample).")
+        .with_callback(|observer| {
+            if let Ok(sample) = mallctl::sample_interval() {
+                observer.observe(sample as u64, &[]);
+            }
+        })
+        .build();
+
+    meter
+        .u64_observable_counter("jemalloc.prof.dumps")
+        .with_description("Number of heap profile dumps.")
+        .with_callback(|observer| observer.observe(mallctl::dump_stats().count, &[]))
+        .build();
+
+    meter
+        .f64_observable_counter("jemalloc.prof.dump.duration")
+        .with_description("Total time spent dumping heap profiles.")
+        .with_unit("s")
+        .with_callback(|observer| {
+            observer.observe(mallctl::dump_stats().duration.as_secs_f64(), &[]);
+        })
+        .build();
+
+    meter
+        .u64_observable_counter("oompanic.allocation_failures")
+        .with_description("Number of failed allocations that caused a panic.")
+        .with_callback(|observer| {
+            let failures = oompanic::failures();
+            for kind in [AllocKind::Alloc, AllocKind::AllocZeroed, AllocKind::Realloc] {
+                observer.observe(failures.get(kind), &[KeyValue::new("kind", kind.to_string())]);
+            }
+        })
+        .build();
+
+    meter
+        .u64_observable_gauge("oompanic.reserve")
+        .with_description("Size of the emergency reserve currently held.")
+        .with_unit("By")
+        .with_callback(|observer| observer.observe(oompanic::reserve_size() as u64, &[]))
+        .build();
+
+    meter
+        .u64_observable_counter("oompanic.reserve.consumed")
+        .with_description(
+            "Number of times the emergency reserve was released on allocation failure.",
+        )
+        .with_callback(|observer| observer.observe(oompanic::reserve_consumed(), &[]))
+        .build();
+}
+
+fn register_arena_counter(
+    meter: &Meter,
+    name: &'static str,
+    description: &'static str,
+    value: fn(&mallctl::AllocCounters) -> u64,
+) {
+    meter
+        .u64_observable_counter(name)
+        .with_description(description)
+        .with_callback(move |observer| {
+            let Ok(arenas) = mallctl::arena_stats() else {
+                return;
+            };
+            for arena in arenas {
+                for (class, counters) in [("small", &arena.small), ("large", &arena.large)] {
+                    let attributes = [
+                        KeyValue::new("arena", i64::from(arena.index)),
+                        KeyValue::new("size_class", class),
+                    ];
+                    observer.observe(value(counters), &attributes);
+                }
+            }
+        })
+        .build();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_sdk::metrics::{
+        data::{AggregatedMetrics, MetricData, ResourceMetrics, ScopeMetrics},
+        InMemoryMetricExporter, PeriodicReader, SdkMeterProvider,
+    };
+
+    #[test]
+    fn test_register() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        register(&provider.meter("microchassis"));
+        provider.force_flush().expect("force_flush");
+
+        let resource_metrics = exporter.get_finished_metrics().expect("get_finished_metrics");
+        let metrics: Vec<_> = resource_metrics
+            .iter()
+            .flat_map(ResourceMetrics::scope_metrics)
+            .flat_map(ScopeMetrics::metrics)
+            .collect();
+
+        for name in [
+            "jemalloc.memory",
+            "jemalloc.arena.allocations",
+            "jemalloc.prof.enabled",
+            "jemalloc.prof.dumps",
+            "jemalloc.prof.dump.duration",
+            "oompanic.allocation_failures",
+        ] {
+            assert!(metrics.iter().any(|m| m.name() == name), "{name} missing");
+        }
+
+        let memory = metrics.iter().find(|m| m.name() == "jemalloc.memory").expect("memory");
+        let gauge = match memory.data() {
+            AggregatedMetrics::U64(MetricData::Gauge(gauge)) => Some(gauge),
+            _ => None,
+        }
+        .expect("jemalloc.memory is a u64 gauge");
+        assert_eq!(6, gauge.data_points().count());
+        let allocated = gauge
+            .data_points()
+            .find(|dp| dp.attributes().any(|kv| kv.value.as_str() == "allocated"))
+            .expect("allocated data point");
+        assert!(allocated.value() > 0);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Audit trail of profiler changes, dumps and purges.
+//!
+//! Every [`AuditLog::record`] emits a `tracing` event with target
+//! `microchassis::audit` and keeps the entry in a bounded in-memory log,
+//! served at `/pprof/audit`.
+
+use lazy_static::lazy_static;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Number of entries kept by the [`global`] log.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// A recorded admin action.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub time: SystemTime,
+    /// Client address, or a name for internal callers, e.g. `psi`.
+    pub caller: String,
+    /// e.g. `POST /pprof/conf`.
+    pub action: String,
+    pub params: String,
+    /// The error message if the action failed or was rejected.
+    pub result: Result<(), String>,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "{}.{:03} {} {} {:?} ",
+            time.as_secs(),
+            time.subsec_millis(),
+            self.caller,
+            self.action,
+            self.params
+        )?;
+        match &self.result {
+            Ok(()) => f.write_str("ok"),
+            Err(e) => write!(f, "error: {}", e.trim_end()),
+        }
+    }
+}
+
+/// Keeps the most recent entries up to a fixed capacity.
+#[derive(Debug)]
+pub struct AuditLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl AuditLog {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    /// Records an action that happened now.
+    pub fn record(&self, caller: &str, action: &str, params: &str, result: Result<(), String>) {
+        match &result {
+            Ok(()) => tracing::info!(target: "microchassis::audit", caller, action, params, "ok"),
+            Err(e) => {
+                tracing::warn!(target: "microchassis::audit", caller, action, params, error = %e.trim_end(), "failed");
+            }
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        let entry = AuditEntry {
+            time: SystemTime::now(),
+            caller: caller.to_owned(),
+            action: action.to_owned(),
+            params: params.to_owned(),
+            result,
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Returns the entries, oldest first.
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect()
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+lazy_static! {
+    static ref GLOBAL: Arc<AuditLog> = Arc::new(AuditLog::default());
+}
+
+/// The process-wide log used by default routers and the PSI watcher.
+#[must_use]
+pub fn global() -> Arc<AuditLog> {
+    Arc::clone(&GLOBAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let log = AuditLog::new(2);
+        log.record("127.0.0.1", "POST /pprof/conf", "prof.active:true", Ok(()));
+        log.record("psi", "dump", "", Err("failed\r\n".to_owned()));
+        log.record("::1", "GET /pprof/heap", "", Ok(()));
+        let entries = log.entries();
+        assert_eq!(2, entries.len());
+        assert_eq!(("psi", "dump"), (entries[0].caller.as_str(), entries[0].action.as_str()));
+        assert!(entries[0].to_string().ends_with(" psi dump \"\" error: failed"), "{}", entries[0]);
+        assert!(entries[1].to_string().ends_with(" ::1 GET /pprof/heap \"\" ok"), "{}", entries[1]);
+    }
+}
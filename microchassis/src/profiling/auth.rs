@@ -0,0 +1,280 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access control for the admin endpoints.
+//!
+//! A [`Policy`] checks the client address, a bearer token and whether the
+//! route mutates state. Custom checks implement [`Authorizer`]. Register
+//! either with [`super::jeprof::RouterBuilder::auth`].
+
+use http::Method;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+/// Address of the connected client. Insert it into the request extensions
+/// before calling the plain `http` router; actix-web provides it itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// The parts of a request relevant for authorization.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthRequest<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    /// Value of the `Authorization` header.
+    pub authorization: Option<&'a str>,
+    pub client: Option<IpAddr>,
+    /// Whether the route changes profiler state.
+    pub mutating: bool,
+    /// Whether the route writes a heap dump, e.g. `GET /pprof/heap`.
+    pub dumps: bool,
+}
+
+/// Why a request was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Missing or invalid credentials, answered with 401.
+    Unauthorized,
+    /// Not allowed regardless of credentials, answered with 403.
+    Forbidden,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => f.write_str("unauthorized"),
+            Self::Forbidden => f.write_str("forbidden"),
+        }
+    }
+}
+
+/// Decides whether a request may be handled.
+pub trait Authorizer: Send + Sync {
+    fn authorize(&self, req: &AuthRequest<'_>) -> Result<(), Rejection>;
+}
+
+impl<F> Authorizer for F
+where
+    F: Fn(&AuthRequest<'_>) -> Result<(), Rejection> + Send + Sync,
+{
+    #[inline]
+    fn authorize(&self, req: &AuthRequest<'_>) -> Result<(), Rejection> {
+        self(req)
+    }
+}
+
+/// A network given as address and prefix length, e.g. `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    /// Returns `None` if `prefix_len` exceeds the address length.
+    #[must_use]
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        (prefix_len <= max).then_some(Self { addr, prefix_len })
+    }
+
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, normalize(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for Network {
+    fn from(addr: IpAddr) -> Self {
+        Self { addr, prefix_len: if addr.is_ipv4() { 32 } else { 128 } }
+    }
+}
+
+// Treats IPv4-mapped IPv6 addresses as IPv4.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// The built-in [`Authorizer`]. Allows everything unless configured.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    token: Option<String>,
+    networks: Option<Vec<Network>>,
+    loopback_only: bool,
+    read_only: bool,
+}
+
+impl Policy {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requires `Authorization: Bearer <token>`.
+    #[must_use]
+    pub fn bearer_token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Only accepts clients from `network`. Can be called multiple times.
+    #[must_use]
+    pub fn allow<N: Into<Network>>(mut self, network: N) -> Self {
+        self.networks.get_or_insert_with(Vec::new).push(network.into());
+        self
+    }
+
+    /// Only accepts clients connecting from a loopback address.
+    #[must_use]
+    pub fn loopback_only(mut self) -> Self {
+        self.loopback_only = true;
+        self
+    }
+
+    /// Rejects routes changing profiler state or writing heap dumps, e.g.
+    /// `POST /pprof/conf` and `GET /pprof/heap`.
+    #[must_use]
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    fn client_allowed(&self, client: Option<IpAddr>) -> bool {
+        if !self.loopback_only && self.networks.is_none() {
+            return true;
+        }
+        let Some(ip) = client.map(normalize) else {
+            return false;
+        };
+        if self.loopback_only && !ip.is_loopback() {
+            return false;
+        }
+        self.networks.as_ref().map_or(true, |nets| nets.iter().any(|n| n.contains(ip)))
+    }
+}
+
+impl Authorizer for Policy {
+    fn authorize(&self, req: &AuthRequest<'_>) -> Result<(), Rejection> {
+        if !self.client_allowed(req.client) {
+            return Err(Rejection::Forbidden);
+        }
+        if let Some(token) = &self.token {
+            let given = req.authorization.and_then(bearer_token);
+            if !given.map_or(false, |given| constant_time_eq(given.as_bytes(), token.as_bytes())) {
+                return Err(Rejection::Unauthorized);
+            }
+        }
+        if self.read_only && (req.mutating || req.dumps) {
+            return Err(Rejection::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the credentials of a `Bearer` authorization. The scheme is
+/// case-insensitive.
+fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("Bearer").then(|| token.trim_start())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn request(client: &str, authorization: Option<&str>, mutating: bool) -> Result<(), Rejection> {
+        request_dump(client, authorization, mutating, false)
+    }
+
+    fn request_dump(
+        client: &str,
+        authorization: Option<&str>,
+        mutating: bool,
+        dumps: bool,
+    ) -> Result<(), Rejection> {
+        let policy = Policy::new()
+            .bearer_token("s3cret")
+            .allow(Network::new("10.1.0.0".parse().expect("ip"), 16).expect("network"))
+            .allow("::1".parse::<IpAddr>().expect("ip"))
+            .read_only();
+        let req = AuthRequest {
+            method: &Method::GET,
+            path: "/pprof/conf",
+            authorization,
+            client: client.parse().ok(),
+            mutating,
+            dumps,
+        };
+        policy.authorize(&req)
+    }
+
+    #[test]
+    fn test_policy() {
+        assert_eq!(Ok(()), request("10.1.2.3", Some("Bearer s3cret"), false));
+        assert_eq!(Ok(()), request("::ffff:10.1.2.3", Some("Bearer s3cret"), false));
+        assert_eq!(Ok(()), request("::1", Some("Bearer s3cret"), false));
+        assert_eq!(Ok(()), request("::1", Some("bearer s3cret"), false));
+        assert_eq!(Ok(()), request("::1", Some("BEARER  s3cret"), false));
+        assert_eq!(Err(Rejection::Unauthorized), request("::1", Some("Basic s3cret"), false));
+        assert_eq!(Err(Rejection::Unauthorized), request("::1", Some("Bearers3cret"), false));
+        assert_eq!(Err(Rejection::Forbidden), request("10.2.0.1", Some("Bearer s3cret"), false));
+        assert_eq!(Err(Rejection::Forbidden), request("", Some("Bearer s3cret"), false));
+        assert_eq!(Err(Rejection::Unauthorized), request("10.1.2.3", None, false));
+        assert_eq!(Err(Rejection::Unauthorized), request("10.1.2.3", Some("Bearer s3cre"), false));
+        assert_eq!(Err(Rejection::Forbidden), request("10.1.2.3", Some("Bearer s3cret"), true));
+        assert_eq!(
+            Err(Rejection::Forbidden),
+            request_dump("10.1.2.3", Some("Bearer s3cret"), false, true)
+        );
+    }
+
+    #[test]
+    fn test_loopback_only() {
+        let policy = Policy::new().loopback_only();
+        let req = |client: IpAddr| AuthRequest {
+            method: &Method::POST,
+            path: "/pprof/conf",
+            authorization: None,
+            client: Some(client),
+            mutating: true,
+            dumps: false,
+        };
+        assert_eq!(Ok(()), policy.authorize(&req(IpAddr::V4(Ipv4Addr::LOCALHOST))));
+        assert_eq!(
+            Err(Rejection::Forbidden),
+            policy.authorize(&req("10.0.0.1".parse().expect("ip")))
+        );
+        assert_eq!(None, Network::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 33));
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Profiler controls used by the HTTP handlers.
+//!
+//! [`Jemalloc`] forwards to [`mallctl`], [`MockBackend`] keeps its state in
+//! memory so handlers can be tested without profiling enabled.
+
+use crate::{
+    cgroup::{self, Cgroup, MemoryStats},
+    profiling::{
+        mallctl::{self, Error},
+        metrics,
+    },
+};
+use std::{
+    io::{self, Seek as _, Write as _},
+    sync::{Mutex, PoisonError},
+};
+
+/// Heap profiler operations.
+pub trait ProfilingBackend: Send + Sync {
+    /// Whether profiling is compiled in and enabled (`opt.prof`).
+    fn enabled(&self) -> Result<bool, Error>;
+
+    /// Whether samples are being collected (`prof.active`).
+    fn active(&self) -> Result<bool, Error>;
+
+    fn set_active(&self, active: bool) -> Result<(), Error>;
+
+    /// Sample interval as log2 of bytes (`prof.lg_sample`).
+    fn sample_interval(&self) -> Result<usize, Error>;
+
+    /// Discards all samples, optionally changing the sample interval.
+    fn reset(&self, sample: Option<usize>) -> Result<(), Error>;
+
+    /// Returns a heap profile in jeprof format.
+    fn dump(&self) -> Result<Vec<u8>, Error>;
+
+    /// Returns a heap profile in jeprof format to be read incrementally.
+    fn dump_reader(&self) -> Result<Box<dyn io::Read + Send>, Error> {
+        Ok(Box::new(io::Cursor::new(self.dump()?)))
+    }
+
+    /// Returns allocator statistics in human-readable form.
+    fn stats(&self) -> Result<Vec<u8>, Error>;
+
+    /// Returns allocator statistics in human-readable form to be read
+    /// incrementally.
+    fn stats_reader(&self) -> Result<Box<dyn io::Read + Send>, Error> {
+        Ok(Box::new(io::Cursor::new(self.stats()?)))
+    }
+
+    /// Returns allocator statistics in jemalloc's JSON format, see
+    /// [`crate::profiling::stats`].
+    fn stats_json(&self) -> Result<Vec<u8>, Error>;
+
+    /// Returns allocator metrics in Prometheus text format.
+    fn metrics(&self) -> Result<String, Error>;
+
+    /// Returns the memory interface values of the process's cgroup.
+    fn cgroup_memory(&self) -> Result<MemoryStats, cgroup::Error>;
+}
+
+/// The backend of the jemalloc global allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Jemalloc;
+
+impl ProfilingBackend for Jemalloc {
+    #[inline]
+    fn enabled(&self) -> Result<bool, Error> {
+        mallctl::enabled()
+    }
+
+    #[inline]
+    fn active(&self) -> Result<bool, Error> {
+        mallctl::active()
+    }
+
+    #[inline]
+    fn set_active(&self, active: bool) -> Result<(), Error> {
+        mallctl::set_active(active)
+    }
+
+    #[inline]
+    fn sample_interval(&self) -> Result<usize, Error> {
+        mallctl::sample_interval()
+    }
+
+    #[inline]
+    fn reset(&self, sample: Option<usize>) -> Result<(), Error> {
+        mallctl::reset(sample)
+    }
+
+    fn dump(&self) -> Result<Vec<u8>, Error> {
+        let f = tempfile::Builder::new().prefix("jemalloc.").suffix(".prof").tempfile()?;
+        let path = f.path().to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "temporary path is not UTF-8")
+        })?;
+        Ok(mallctl::dump(Some(path))?.unwrap_or_default())
+    }
+
+    /// Reads from the dump file, which is deleted when the reader is dropped.
+    fn dump_reader(&self) -> Result<Box<dyn io::Read + Send>, Error> {
+        let f = tempfile::Builder::new().prefix("jemalloc.").suffix(".prof").tempfile()?;
+        let path = f.path().to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "temporary path is not UTF-8")
+        })?;
+        mallctl::dump_to(path)?;
+        Ok(Box::new(f))
+    }
+
+    #[inline]
+    fn stats(&self) -> Result<Vec<u8>, Error> {
+        mallctl::stats()
+    }
+
+    /// Reads from an anonymous temporary file, so the output is not buffered
+    /// in memory.
+    fn stats_reader(&self) -> Result<Box<dyn io::Read + Send>, Error> {
+        let mut f = tempfile::tempfile()?;
+        let mut writer = io::BufWriter::new(&mut f);
+        mallctl::stats_to(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        f.seek(io::SeekFrom::Start(0))?;
+        Ok(Box::new(f))
+    }
+
+    #[inline]
+    fn stats_json(&self) -> Result<Vec<u8>, Error> {
+        mallctl::advance_epoch()?;
+        mallctl::stats_json()
+    }
+
+    #[inline]
+    fn metrics(&self) -> Result<String, Error> {
+        metrics::render()
+    }
+
+    #[inline]
+    fn cgroup_memory(&self) -> Result<MemoryStats, cgroup::Error> {
+        Cgroup::discover()?.memory()
+    }
+}
+
+/// State of a [`MockBackend`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockState {
+    pub enabled: bool,
+    pub active: bool,
+    pub lg_sample: usize,
+    /// Returned by every dump.
+    pub profile: Vec<u8>,
+    pub stats: Vec<u8>,
+    pub stats_json: Vec<u8>,
+    pub metrics: String,
+    /// `None` as if the process wasn't in a cgroup.
+    pub cgroup: Option<MemoryStats>,
+    pub resets: u64,
+    pub dumps: u64,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            active: false,
+            lg_sample: 19,
+            profile: b"heap_v2/524288\n  t*: 0: 0 [0: 0]\n".to_vec(),
+            stats: b"___ Begin jemalloc statistics ___\n___ End jemalloc statistics ___\n".to_vec(),
+            stats_json: br#"{"jemalloc":{"version":"mock","stats":{"allocated":1,"active":2,"metadata":3,"resident":4,"mapped":5,"retained":6},"stats.arenas":{"merged":{"nthreads":1,"pactive":1,"pdirty":0,"pmuzzy":0,"small":{"allocated":1,"nmalloc":1,"ndalloc":0,"nrequests":1},"large":{"allocated":0,"nmalloc":0,"ndalloc":0,"nrequests":0}}}}}"#.to_vec(),
+            metrics: "# TYPE jemalloc_allocated_bytes gauge\njemalloc_allocated_bytes 1\n"
+                .to_owned(),
+            cgroup: Some(MemoryStats { max: Some(1 << 30), current: 1 << 20, ..Default::default() }),
+            resets: 0,
+            dumps: 0,
+        }
+    }
+}
+
+/// An in-memory backend for tests.
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    state: Mutex<MockState>,
+}
+
+impl MockBackend {
+    #[must_use]
+    pub fn new(state: MockState) -> Self {
+        Self { state: Mutex::new(state) }
+    }
+
+    /// Returns a copy of the current state.
+    pub fn state(&self) -> MockState {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn if_enabled<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut MockState) -> T,
+    {
+        let mut state = self.lock();
+        if state.enabled {
+            Ok(f(&mut state))
+        } else {
+            Err(Error::ProfilingDisabled)
+        }
+    }
+}
+
+impl ProfilingBackend for MockBackend {
+    fn enabled(&self) -> Result<bool, Error> {
+        Ok(self.lock().enabled)
+    }
+
+    fn active(&self) -> Result<bool, Error> {
+        self.if_enabled(|s| s.active)
+    }
+
+    fn set_active(&self, active: bool) -> Result<(), Error> {
+        self.if_enabled(|s| s.active = active)
+    }
+
+    fn sample_interval(&self) -> Result<usize, Error> {
+        self.if_enabled(|s| s.lg_sample)
+    }
+
+    fn reset(&self, sample: Option<usize>) -> Result<(), Error> {
+        self.if_enabled(|s| {
+            s.resets += 1;
+            if let Some(sample) = sample {
+                s.lg_sample = sample;
+            }
+        })
+    }
+
+    fn dump(&self) -> Result<Vec<u8>, Error> {
+        self.if_enabled(|s| {
+            s.dumps += 1;
+            s.profile.clone()
+        })
+    }
+
+    fn stats(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.lock().stats.clone())
+    }
+
+    fn stats_json(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.lock().stats_json.clone())
+    }
+
+    fn metrics(&self) -> Result<String, Error> {
+        Ok(self.lock().metrics.clone())
+    }
+
+    fn cgroup_memory(&self) -> Result<MemoryStats, cgroup::Error> {
+        self.lock().cgroup.ok_or(cgroup::Error::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read as _;
+
+    #[test]
+    fn test_mock() {
+        let mock = MockBackend::default();
+        mock.set_active(true).expect("set_active");
+        mock.reset(Some(10)).expect("reset");
+        assert_eq!(MockState::default().profile, mock.dump().expect("dump"));
+        let state = mock.state();
+        assert_eq!((true, 10, 1, 1), (state.active, state.lg_sample, state.resets, state.dumps));
+
+        let mock = MockBackend::new(MockState { enabled: false, ..Default::default() });
+        assert!(matches!(mock.active(), Err(Error::ProfilingDisabled)));
+        assert!(matches!(mock.dump(), Err(Error::ProfilingDisabled)));
+    }
+
+    #[test]
+    fn test_jemalloc_dump() {
+        let profile = Jemalloc.dump().expect("dump");
+        assert!(profile.starts_with(b"heap_v2/"));
+
+        let mut streamed = Vec::new();
+        Jemalloc.dump_reader().expect("dump_reader").read_to_end(&mut streamed).expect("read");
+        assert!(streamed.starts_with(b"heap_v2/"));
+    }
+
+    #[test]
+    fn test_jemalloc_stats() {
+        let mut streamed = Vec::new();
+        Jemalloc.stats_reader().expect("stats_reader").read_to_end(&mut streamed).expect("read");
+        assert!(streamed.starts_with(b"___ Begin jemalloc statistics ___\n"));
+        assert!(streamed.ends_with(b"--- End jemalloc statistics ---\n"));
+    }
+}
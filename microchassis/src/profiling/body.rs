@@ -0,0 +1,195 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Response bodies, either buffered or streamed in chunks.
+//!
+//! Streamed bodies are read on demand, e.g. from a dump file, so a large
+//! profile is never held in memory as a whole. Gzip compression happens on
+//! the fly while reading.
+
+use flate2::{read::GzEncoder, Compression};
+use std::{
+    fmt,
+    io::{self, Read as _},
+};
+
+/// Size of the chunks produced by [`Body::chunks`].
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bodies smaller than this are not worth compressing.
+pub const MIN_GZIP_SIZE: usize = 1024;
+
+/// A response body.
+pub enum Body {
+    Full(Vec<u8>),
+    Stream(Box<dyn io::Read + Send>),
+}
+
+impl Body {
+    /// The length of a buffered body, `None` for streams.
+    #[must_use]
+    pub fn known_len(&self) -> Option<usize> {
+        match self {
+            Self::Full(bytes) => Some(bytes.len()),
+            Self::Stream(_) => None,
+        }
+    }
+
+    /// Whether compressing is likely worthwhile.
+    #[must_use]
+    pub fn should_gzip(&self) -> bool {
+        self.known_len().map_or(true, |len| len >= MIN_GZIP_SIZE)
+    }
+
+    /// Compresses the body with gzip while it is read.
+    #[must_use]
+    pub fn gzip(self) -> Self {
+        Self::Stream(Box::new(GzEncoder::new(self.into_reader(), Compression::default())))
+    }
+
+    #[must_use]
+    pub fn into_reader(self) -> Box<dyn io::Read + Send> {
+        match self {
+            Self::Full(bytes) => Box::new(io::Cursor::new(bytes)),
+            Self::Stream(reader) => reader,
+        }
+    }
+
+    /// Reads the whole body.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Full(bytes) => Ok(bytes),
+            Self::Stream(mut reader) => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Splits the body into chunks of up to [`CHUNK_SIZE`] bytes, read as
+    /// they are consumed.
+    #[must_use]
+    pub fn chunks(self) -> Chunks {
+        Chunks { body: Some(self) }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(bytes) => f.debug_tuple("Full").field(&bytes.len()).finish(),
+            Self::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Full(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Self {
+        Self::Full(s.into_bytes())
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(s: &'static str) -> Self {
+        Self::Full(s.as_bytes().to_vec())
+    }
+}
+
+/// Iterator over the chunks of a [`Body`]. Ends after the first error.
+#[derive(Debug)]
+pub struct Chunks {
+    body: Option<Body>,
+}
+
+impl Iterator for Chunks {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.body.take()? {
+            Body::Full(bytes) if bytes.is_empty() => None,
+            Body::Full(mut bytes) => {
+                if bytes.len() > CHUNK_SIZE {
+                    self.body = Some(Body::Full(bytes.split_off(CHUNK_SIZE)));
+                }
+                Some(Ok(bytes))
+            }
+            Body::Stream(mut reader) => {
+                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                match io::Read::take(&mut reader, CHUNK_SIZE as u64).read_to_end(&mut chunk) {
+                    Ok(0) => None,
+                    Ok(_) => {
+                        self.body = Some(Body::Stream(reader));
+                        Some(Ok(chunk))
+                    }
+                    Err(e) => Some(Err(e)),
+                }
+            }
+        }
+    }
+}
+
+/// Whether an `Accept-Encoding` header value allows gzip.
+#[must_use]
+pub fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding.map_or(false, |value| {
+        value.split(',').any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let q = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(1.0, |q| q.parse::<f32>().unwrap_or(0.0));
+            (name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip")) && q > 0.0
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn test_chunks() {
+        let data: Vec<u8> = (0..=250).cycle().take(CHUNK_SIZE * 2 + 10).collect();
+        let lens = |body: Body| body.chunks().map(|c| c.expect("chunk").len()).collect::<Vec<_>>();
+        assert_eq!(vec![CHUNK_SIZE, CHUNK_SIZE, 10], lens(Body::from(data.clone())));
+        let stream = Body::Stream(Box::new(io::Cursor::new(data.clone())));
+        assert_eq!(vec![CHUNK_SIZE, CHUNK_SIZE, 10], lens(stream));
+        assert!(Body::from(Vec::new()).chunks().next().is_none());
+
+        let gzipped = Body::from(data.clone()).gzip();
+        assert_eq!(None, gzipped.known_len());
+        let compressed: Vec<u8> = gzipped.chunks().flat_map(|c| c.expect("chunk")).collect();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_slice()).read_to_end(&mut decompressed).expect("gunzip");
+        assert_eq!(data, decompressed);
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(accepts_gzip(Some("gzip")));
+        assert!(accepts_gzip(Some("br, GZIP;q=0.5")));
+        assert!(accepts_gzip(Some("deflate, x-gzip")));
+        assert!(!accepts_gzip(Some("gzip;q=0, br")));
+        assert!(!accepts_gzip(Some("identity")));
+        assert!(!accepts_gzip(None));
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-process parsing of jemalloc heap profiles (`heap_v2` format).
+//!
+//! Sampled counts are scaled to estimates like jeprof does, and stacks are
+//! symbolized with `backtrace`, so the dashboard needs neither jeprof nor the
+//! binary on the client side.
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("not a heap_v2 profile")]
+    InvalidHeader,
+
+    #[error("invalid profile line {0}: {1:?}")]
+    InvalidLine(usize, String),
+}
+
+/// A parsed heap profile.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Average bytes between samples, `2^lg_prof_sample`.
+    pub sample_period: u64,
+    pub samples: Vec<Sample>,
+}
+
+/// Live allocations of one stack.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sample {
+    /// Return addresses, leaf first.
+    pub addrs: Vec<u64>,
+    /// Estimated number of live objects.
+    pub objects: u64,
+    /// Estimated number of live bytes.
+    pub bytes: u64,
+}
+
+/// Parses a profile as returned by `prof.dump`. Stacks without live
+/// allocations are skipped.
+pub fn parse(profile: &[u8]) -> Result<Profile, Error> {
+    let profile = String::from_utf8_lossy(profile);
+    let mut lines = profile.lines().enumerate();
+    let sample_period = lines
+        .next()
+        .and_then(|(_, header)| header.trim().strip_prefix("heap_v2/"))
+        .and_then(|period| period.parse().ok())
+        .ok_or(Error::InvalidHeader)?;
+
+    let invalid = |n: usize, line: &str| Error::InvalidLine(n + 1, line.to_owned());
+    let mut samples = Vec::new();
+    let mut addrs: Option<Vec<u64>> = None;
+    for (n, line) in lines {
+        let line = line.trim();
+        if line.starts_with("MAPPED_LIBRARIES:") {
+            break;
+        } else if let Some(stack) = line.strip_prefix('@') {
+            let stack = stack
+                .split_whitespace()
+                .map(|addr| u64::from_str_radix(addr.trim_start_matches("0x"), 16))
+                .collect::<Result<_, _>>()
+                .map_err(|_| invalid(n, line))?;
+            addrs = Some(stack);
+        } else if let Some(counts) = line.strip_prefix("t*:") {
+            // Totals of the whole profile precede the first stack.
+            let Some(addrs) = addrs.take() else {
+                continue;
+            };
+            let (objects, bytes) = parse_counts(counts).ok_or_else(|| invalid(n, line))?;
+            if objects > 0 {
+                let (objects, bytes) = scale(objects, bytes, sample_period);
+                samples.push(Sample { addrs, objects, bytes });
+            }
+        }
+        // Per-thread counts (`t<i>:`) and empty lines are ignored.
+    }
+    Ok(Profile { sample_period, samples })
+}
+
+/// Parses ` <objects>: <bytes> [<accum objects>: <accum bytes>]`.
+fn parse_counts(counts: &str) -> Option<(u64, u64)> {
+    let (current, _) = counts.split_once('[').unwrap_or((counts, ""));
+    let (objects, bytes) = current.split_once(':')?;
+    Some((objects.trim().parse().ok()?, bytes.trim().parse().ok()?))
+}
+
+/// Sampling records an allocation of `size` bytes with probability
+/// `1 - exp(-size / period)`; divides the counts by that.
+#[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn scale(objects: u64, bytes: u64, period: u64) -> (u64, u64) {
+    if period <= 1 {
+        return (objects, bytes);
+    }
+    let size = bytes as f64 / objects as f64;
+    let scale = 1.0 / (1.0 - (-size / period as f64).exp());
+    ((objects as f64 * scale).round() as u64, (bytes as f64 * scale).round() as u64)
+}
+
+/// Allocator entry points. These and all frames called by them, i.e. jemalloc
+/// and profiler internals, are dropped from stacks.
+const ALLOCATOR_FRAMES: &[&str] = &[
+    "_rjem_",
+    "tikv_jemallocator::",
+    "<tikv_jemallocator::",
+    "__rust_alloc",
+    "__rust_realloc",
+    "__rustc::__rust_alloc",
+    "__rustc::__rust_realloc",
+    "__rdl_",
+    "__rg_",
+    "alloc::alloc::",
+];
+
+/// A symbolized profile, served as JSON by `/pprof/ui/profile`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Symbolized {
+    pub sample_period: u64,
+    /// Function names, referenced by index from [`Stack::frames`].
+    pub functions: Vec<String>,
+    pub stacks: Vec<Stack>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Stack {
+    /// Indices into [`Symbolized::functions`], leaf first. Inlined functions
+    /// get frames of their own.
+    pub frames: Vec<usize>,
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+impl Profile {
+    /// Resolves all addresses to function names. Unresolvable addresses are
+    /// kept in hex.
+    #[must_use]
+    pub fn symbolize(&self) -> Symbolized {
+        let mut functions = Vec::new();
+        let mut indices = HashMap::<String, usize>::new();
+        let mut resolved = HashMap::<u64, Vec<usize>>::new();
+        let mut stacks = Vec::with_capacity(self.samples.len());
+        for sample in &self.samples {
+            let mut frames = Vec::new();
+            for (i, &addr) in sample.addrs.iter().enumerate() {
+                // Return addresses point after the call, except for the leaf.
+                let pc = if i == 0 { addr } else { addr.saturating_sub(1) };
+                let ids = resolved.entry(pc).or_insert_with(|| {
+                    resolve(pc)
+                        .into_iter()
+                        .map(|name| {
+                            *indices.entry(name).or_insert_with_key(|name| {
+                                functions.push(name.clone());
+                                functions.len() - 1
+                            })
+                        })
+                        .collect()
+                });
+                frames.extend_from_slice(ids);
+            }
+            let skip = frames
+                .iter()
+                .rposition(|&id| ALLOCATOR_FRAMES.iter().any(|p| functions[id].starts_with(p)))
+                .map_or(0, |i| i + 1);
+            // Keep the outermost allocator frame if nothing else is left.
+            frames.drain(..skip.min(frames.len().saturating_sub(1)));
+            stacks.push(Stack { frames, objects: sample.objects, bytes: sample.bytes });
+        }
+        Symbolized { sample_period: self.sample_period, functions, stacks }
+    }
+}
+
+/// Returns the function at `pc` and the functions inlined into it, innermost
+/// first.
+fn resolve(pc: u64) -> Vec<String> {
+    let mut names = Vec::new();
+    backtrace::resolve(pc as *mut _, |symbol| {
+        if let Some(name) = symbol.name() {
+            names.push(format!("{name:#}"));
+        }
+    });
+    if names.is_empty() {
+        names.push(format!("{pc:#x}"));
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &[u8] = b"heap_v2/524288
+  t*: 3: 1572864 [0: 0]
+  t0: 3: 1572864 [0: 0]
+@ 0x10 0x21 0x31
+  t*: 2: 1048576 [0: 0]
+  t0: 2: 1048576 [0: 0]
+@ 0x10 0x41
+  t*: 1: 524288 [0: 0]
+  t0: 1: 524288 [0: 0]
+@ 0x50
+  t*: 0: 0 [0: 0]
+
+MAPPED_LIBRARIES:
+55d0a5e00000-55d0a5e2a000 r--p 00000000 fe:00 1 /bin/app
+";
+
+    #[test]
+    fn test_parse() {
+        let profile = parse(PROFILE).expect("parse");
+        assert_eq!(524_288, profile.sample_period);
+        assert_eq!(2, profile.samples.len());
+        // 512KiB objects are sampled with probability 1 - 1/e.
+        let sample = &profile.samples[0];
+        assert_eq!(
+            (vec![0x10, 0x21, 0x31], 3, 1_658_823),
+            (sample.addrs.clone(), sample.objects, sample.bytes)
+        );
+        assert_eq!(vec![0x10, 0x41], profile.samples[1].addrs);
+
+        let unsampled = parse(b"heap_v2/1\n@ 0x1\n  t*: 4: 64 [0: 0]\n").expect("parse");
+        assert_eq!((4, 64), (unsampled.samples[0].objects, unsampled.samples[0].bytes));
+
+        assert_eq!(Err(Error::InvalidHeader), parse(b"heap_v3/1\n"));
+        assert_eq!(Err(Error::InvalidHeader), parse(b""));
+        assert_eq!(
+            Err(Error::InvalidLine(2, "@ 0xzz".to_owned())),
+            parse(b"heap_v2/1\n@ 0xzz\n  t*: 1: 1 [0: 0]\n")
+        );
+        assert_eq!(
+            Err(Error::InvalidLine(3, "t*: x".to_owned())),
+            parse(b"heap_v2/1\n@ 0x1\n  t*: x\n")
+        );
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let addr = test_symbolize as *const () as u64 + 1;
+        let profile = Profile {
+            sample_period: 1,
+            samples: vec![
+                Sample { addrs: vec![addr, 0x10], objects: 1, bytes: 8 },
+                Sample { addrs: vec![addr], objects: 2, bytes: 16 },
+            ],
+        };
+        let symbolized = profile.symbolize();
+        assert_eq!(vec![1, 2], symbolized.stacks.iter().map(|s| s.objects).collect::<Vec<_>>());
+        let leaf = symbolized.stacks[0].frames[0];
+        assert!(symbolized.functions[leaf].ends_with("test_symbolize"), "{symbolized:?}");
+        assert_eq!(vec![leaf], symbolized.stacks[1].frames);
+        let caller = symbolized.stacks[0].frames[1];
+        assert_eq!("0xf", symbolized.functions[caller]);
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Browsable index of the admin routes, served at `GET /pprof/`.
+//!
+//! The page is rendered from the router's routes, so it only offers actions
+//! whose endpoints are registered. Forms post `application/x-www-form-urlencoded`
+//! bodies, which `POST /conf` accepts in addition to query parameters.
+
+use crate::profiling::backend::ProfilingBackend;
+use serde::Serialize;
+use std::fmt::Write as _;
+
+/// A route as listed on the index page.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    pub method: String,
+    /// Full path including the router prefix.
+    pub path: String,
+    pub description: String,
+}
+
+/// Profiler state shown above the routes. `active` and `lg_sample` are only
+/// known if profiling is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct State {
+    pub enabled: bool,
+    pub active: Option<bool>,
+    pub lg_sample: Option<usize>,
+}
+
+impl State {
+    #[must_use]
+    pub fn read(backend: &dyn ProfilingBackend) -> Self {
+        let enabled = backend.enabled().unwrap_or(false);
+        Self {
+            enabled,
+            active: backend.active().ok().filter(|_| enabled),
+            lg_sample: backend.sample_interval().ok().filter(|_| enabled),
+        }
+    }
+}
+
+/// Body of `GET /pprof/` in JSON format.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Index<'a> {
+    pub profiling: State,
+    pub routes: &'a [Entry],
+}
+
+/// Renders the index page.
+#[must_use]
+pub fn html(prefix: &str, state: State, routes: &[Entry]) -> String {
+    let has = |method: &str, path: &str| {
+        let path = format!("{prefix}{path}");
+        routes.iter().any(|e| e.method == method && e.path == path)
+    };
+    let prefix = escape(prefix);
+
+    let mut html = String::from(concat!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n",
+        "<title>microchassis admin</title>\n",
+        "<style>body{font-family:sans-serif}td{padding:2px 12px 2px 0}</style>\n",
+        "</head>\n<body>\n<h1>microchassis admin</h1>\n",
+    ));
+
+    html.push_str("<h2>Profiling</h2>\n<p>");
+    match (state.enabled, state.active, state.lg_sample) {
+        (false, _, _) => {
+            html.push_str("not enabled (start with <code>MALLOC_CONF=prof:true</code>)");
+        }
+        (true, active, lg_sample) => {
+            let active = active.map_or("unknown", |a| if a { "active" } else { "inactive" });
+            let _ = write!(html, "enabled, {active}");
+            if let Some(lg_sample) = lg_sample {
+                let _ = write!(html, ", lg_sample {lg_sample}");
+                if let Some(bytes) =
+                    u32::try_from(lg_sample).ok().and_then(|n| 1_u64.checked_shl(n))
+                {
+                    let _ = write!(html, " (every {bytes} bytes)");
+                }
+            }
+        }
+    }
+    html.push_str("</p>\n");
+
+    html.push_str("<h2>Actions</h2>\n<ul>\n");
+    if state.enabled && has("POST", "/conf") {
+        let active = state.active.unwrap_or(false);
+        let _ = writeln!(
+            html,
+            "<li><form method=\"post\" action=\"{prefix}/conf\">\
+             <input type=\"hidden\" name=\"prof.active\" value=\"{}\">\
+             <button>{}</button></form></li>",
+            !active,
+            if active { "Deactivate" } else { "Activate" }
+        );
+        let _ = writeln!(
+            html,
+            "<li><form method=\"post\" action=\"{prefix}/conf\">\
+             <label>lg_sample <input type=\"number\" name=\"prof.reset\" min=\"0\" max=\"63\" \
+             value=\"{}\"></label> <button>Reset</button></form></li>",
+            state.lg_sample.unwrap_or(19)
+        );
+    }
+    if has("GET", "/ui") {
+        let _ = writeln!(
+            html,
+            "<li><a href=\"{prefix}/ui\">Dashboard</a>: flamegraph, top allocations, \
+             snapshot diffs and stats over time</li>"
+        );
+    }
+    if has("GET", "/heap") {
+        let _ = writeln!(html, "<li><a href=\"{prefix}/heap\">Download heap profile</a></li>");
+        let _ = writeln!(
+            html,
+            "<li>Flamegraph: <code>jeprof --raw 'http://HOST{prefix}/heap' &gt;heap.prof; \
+             jeprof --collapsed heap.prof | flamegraph.pl --reverse --invert &gt;heap.svg</code></li>"
+        );
+    }
+    if has("GET", "/stats") {
+        let _ = writeln!(
+            html,
+            "<li><a href=\"{prefix}/stats\">Stats</a> \
+             (<a href=\"{prefix}/stats?format=json\">JSON</a>)</li>"
+        );
+    }
+    html.push_str("</ul>\n");
+
+    html.push_str("<h2>Endpoints</h2>\n<table>\n");
+    for entry in routes {
+        let path = escape(&entry.path);
+        let link =
+            if entry.method == "GET" { format!("<a href=\"{path}\">{path}</a>") } else { path };
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{link}</td><td>{}</td></tr>",
+            escape(&entry.method),
+            escape(&entry.description)
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(method: &str, path: &str, description: &str) -> Entry {
+        Entry {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            description: description.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_html() {
+        let routes = vec![
+            entry("GET", "/p/heap", "Heap profile"),
+            entry("POST", "/p/conf", "Change <conf>"),
+        ];
+        let state = State { enabled: true, active: Some(false), lg_sample: Some(10) };
+        let html = html("/p", state, &routes);
+        assert!(html.contains("enabled, inactive, lg_sample 10 (every 1024 bytes)"), "{html}");
+        assert!(html.contains("name=\"prof.active\" value=\"true\""), "{html}");
+        assert!(html.contains("<a href=\"/p/heap\">Download heap profile</a>"), "{html}");
+        assert!(html.contains("<td>POST</td><td>/p/conf</td><td>Change &lt;conf&gt;</td>"));
+        assert!(!html.contains("/p/stats"), "{html}");
+
+        let state = State { enabled: false, active: None, lg_sample: None };
+        let html = super::html("/p", state, &routes);
+        assert!(html.contains("not enabled"), "{html}");
+        assert!(!html.contains("prof.reset"), "{html}");
+    }
+}
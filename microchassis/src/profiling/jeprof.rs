@@ -13,236 +13,1092 @@
 // limitations under the License.
 
 //! Contains HTTP handler for jeprof support (/pprof/heap).
+//!
 //! Based on <https://gperftools.github.io/gperftools/pprof_remote_servers.html>,
 //! <https://jemalloc.net/jemalloc.3.html#mallctl_namespace>,
 //! <https://github.com/jemalloc/jemalloc/blob/master/bin/jeprof.in>.
+//!
+//! Responses are plain text unless the client sends `Accept: application/json`
+//! or `?format=json` (`?format=text` overrides the header). `/heap`,
+//! `/metrics` and the dashboard (`/ui`) always use their native formats.
 
-use crate::profiling::mallctl;
+use crate::profiling::{
+    audit::{self, AuditLog},
+    auth::{AuthRequest, Authorizer, ClientAddr, Rejection},
+    backend::{Jemalloc, ProfilingBackend},
+    body::{self, Body},
+    heap, index, mallctl, query, stats,
+};
 use http::{header, Method, Request, Response, StatusCode};
-use std::{collections::HashMap, env, fmt};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    net::IpAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-#[inline]
-pub fn router(req: Request<Vec<u8>>) -> http::Result<Response<Vec<u8>>> {
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/pprof/conf") => JeprofHandler(get_pprof_conf_handler).call(req),
-        (&Method::POST, "/pprof/conf") => JeprofHandler(post_pprof_conf_handler).call(req),
-        (&Method::GET, "/pprof/heap") => JeprofHandler(get_pprof_heap_handler).call(req),
-        (&Method::GET, "/pprof/cmdline") => JeprofHandler(get_pprof_cmdline_handler).call(req),
-        (&Method::GET, "/pprof/symbol") => JeprofHandler(get_pprof_symbol_handler).call(req),
-        (&Method::POST, "/pprof/symbol") => JeprofHandler(post_pprof_symbol_handler).call(req),
-        (&Method::GET, "/pprof/stats") => JeprofHandler(get_pprof_stats_handler).call(req),
-        _ => {
-            let body = b"Bad Request\r\n";
-            Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .header(header::CONTENT_TYPE, "application/octet-stream")
-                .header(header::CONTENT_LENGTH, body.len())
-                .body(body.to_vec())
+/// Requests with larger bodies are answered with 413.
+pub const MAX_BODY_SIZE: usize = 1 << 20;
+
+/// A successful handler response.
+#[derive(Debug)]
+pub struct Reply {
+    pub body: Body,
+    pub content_type: &'static str,
+    /// Sent as a download with this filename if set.
+    pub filename: Option<String>,
+}
+
+impl Reply {
+    /// A `text/plain` response.
+    pub fn text<B: Into<Vec<u8>>>(body: B) -> Self {
+        Self {
+            body: Body::Full(body.into()),
+            content_type: "text/plain; charset=UTF-8",
+            filename: None,
+        }
+    }
+
+    /// A `text/html` response.
+    pub fn html<B: Into<Vec<u8>>>(body: B) -> Self {
+        Self {
+            body: Body::Full(body.into()),
+            content_type: "text/html; charset=UTF-8",
+            filename: None,
+        }
+    }
+
+    /// An `application/json` response.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> HandlerResult {
+        let body = serde_json::to_vec(value)
+            .map_err(|e| ErrorResponse::Internal(format!("failed to serialize: {e}")))?;
+        Ok(Self { body: Body::Full(body), content_type: "application/json", filename: None })
+    }
+
+    /// An `application/octet-stream` download, e.g. a streamed dump.
+    #[must_use]
+    pub fn attachment<B: Into<Body>>(body: B, filename: String) -> Self {
+        Self {
+            body: body.into(),
+            content_type: "application/octet-stream",
+            filename: Some(filename),
+        }
+    }
+
+    /// Compresses the body if the client accepts gzip and it is worth it.
+    /// Returns whether it did.
+    fn encode(&mut self, accept_gzip: bool) -> bool {
+        if !accept_gzip || !self.body.should_gzip() {
+            return false;
+        }
+        let body = std::mem::replace(&mut self.body, Body::Full(Vec::new()));
+        self.body = body.gzip();
+        true
+    }
+
+    fn into_response(mut self, accept_gzip: bool) -> http::Result<Response<Body>> {
+        let mut resp = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, self.content_type)
+            .header(header::VARY, "accept-encoding");
+        if self.encode(accept_gzip) {
+            resp = resp.header(header::CONTENT_ENCODING, "gzip");
+        }
+        if let Some(len) = self.body.known_len() {
+            resp = resp.header(header::CONTENT_LENGTH, len);
         }
+        if let Some(filename) = self.filename {
+            resp = resp.header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            );
+        }
+        resp.body(self.body)
+    }
+}
+
+pub type HandlerResult = Result<Reply, ErrorResponse>;
+
+type HandlerFn = fn(&dyn ProfilingBackend, &[u8], &HashMap<String, String>) -> HandlerResult;
+
+/// A request handler. Receives the backend, the request body and the query
+/// parameters, where `format` is always set to `json` or `text`.
+pub type Handler = Arc<
+    dyn Fn(&dyn ProfilingBackend, &[u8], &HashMap<String, String>) -> HandlerResult + Send + Sync,
+>;
+
+/// The built-in endpoints, relative to the router prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// GET and POST `/conf`.
+    Conf,
+    /// GET `/heap`.
+    Heap,
+    /// GET `/cmdline`.
+    Cmdline,
+    /// GET and POST `/symbol`.
+    Symbol,
+    /// GET `/stats`.
+    Stats,
+    /// GET `/metrics`.
+    Metrics,
+    /// GET `/cgroup`.
+    Cgroup,
+    /// GET `/audit`.
+    Audit,
+    /// GET `/`, an HTML index of all routes.
+    Index,
+    /// GET `/ui`, the memory dashboard, and GET `/ui/profile`, a symbolized
+    /// heap profile in JSON.
+    Dashboard,
+}
+
+impl Endpoint {
+    pub const ALL: [Self; 10] = [
+        Self::Conf,
+        Self::Heap,
+        Self::Cmdline,
+        Self::Symbol,
+        Self::Stats,
+        Self::Metrics,
+        Self::Cgroup,
+        Self::Audit,
+        Self::Dashboard,
+        Self::Index,
+    ];
+
+    /// Returns method, path, description and handler of each route. The
+    /// index lists the other routes and is added by [`RouterBuilder::build`].
+    fn routes(
+        self,
+        prefix: &str,
+        log: &Arc<AuditLog>,
+    ) -> Vec<(Method, &'static str, &'static str, Handler)> {
+        let routes: Vec<(Method, &'static str, &'static str, HandlerFn)> = match self {
+            Self::Conf => vec![
+                (
+                    Method::GET,
+                    "/conf",
+                    "Profiler configuration (prof.active, prof.lg_sample)",
+                    get_pprof_conf_handler,
+                ),
+                (
+                    Method::POST,
+                    "/conf",
+                    "Set prof.active=<bool> or reset with prof.reset=<lg_sample>",
+                    post_pprof_conf_handler,
+                ),
+            ],
+            Self::Heap => vec![(
+                Method::GET,
+                "/heap",
+                "Heap profile in jeprof format",
+                get_pprof_heap_handler,
+            )],
+            Self::Cmdline => vec![(
+                Method::GET,
+                "/cmdline",
+                "Command line of the process",
+                get_pprof_cmdline_handler,
+            )],
+            Self::Symbol => vec![
+                (Method::GET, "/symbol", "Symbol lookup support", get_pprof_symbol_handler),
+                (
+                    Method::POST,
+                    "/symbol",
+                    "Resolves +-separated addresses to symbols",
+                    post_pprof_symbol_handler,
+                ),
+            ],
+            Self::Stats => {
+                vec![(Method::GET, "/stats", "jemalloc statistics", get_pprof_stats_handler)]
+            }
+            Self::Metrics => vec![(
+                Method::GET,
+                "/metrics",
+                "Allocator and profiling metrics in Prometheus format",
+                get_pprof_metrics_handler,
+            )],
+            Self::Cgroup => {
+                vec![(Method::GET, "/cgroup", "cgroup memory statistics", get_pprof_cgroup_handler)]
+            }
+            Self::Audit => {
+                let log = Arc::clone(log);
+                let handler =
+                    move |_: &dyn ProfilingBackend, _: &[u8], params: &HashMap<String, String>| {
+                        get_pprof_audit_handler(&log, params)
+                    };
+                return vec![(
+                    Method::GET,
+                    "/audit",
+                    "Recent configuration changes and dumps",
+                    Arc::new(handler),
+                )];
+            }
+            Self::Dashboard => {
+                let page = dashboard_page(prefix);
+                let handler = move |_: &dyn ProfilingBackend, _: &[u8], _: &HashMap<_, _>| {
+                    Ok(Reply::html(page.clone()))
+                };
+                return vec![
+                    (
+                        Method::GET,
+                        "/ui",
+                        "Memory dashboard: flamegraph, top, diff, stats",
+                        Arc::new(handler),
+                    ),
+                    (
+                        Method::GET,
+                        "/ui/profile",
+                        "Symbolized heap profile in JSON",
+                        Arc::new(get_pprof_ui_profile_handler),
+                    ),
+                ];
+            }
+            Self::Index => Vec::new(),
+        };
+        routes
+            .into_iter()
+            .map(|(method, path, description, f)| {
+                (method, path, description, Arc::new(f) as Handler)
+            })
+            .collect()
     }
 }
 
+/// Configures a [`Router`]: mount prefix, backend, enabled endpoints and
+/// custom handlers.
+///
+/// ```ignore
+/// let router = RouterBuilder::new()
+///     .prefix("/debug/pprof")
+///     .disable(Endpoint::Cmdline)
+///     .handler(Method::GET, "/build", |_, _, _| Ok(Reply::text("v1.2.3\r\n")))
+///     .auth(Policy::new().bearer_token(token).read_only())
+///     .build();
+/// ```
+pub struct RouterBuilder {
+    prefix: String,
+    backend: Arc<dyn ProfilingBackend>,
+    endpoints: Vec<Endpoint>,
+    custom: Vec<Route>,
+    auth: Option<Arc<dyn Authorizer>>,
+    audit: Arc<AuditLog>,
+}
+
+impl RouterBuilder {
+    /// All endpoints under `/pprof` with the jemalloc backend.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            prefix: "/pprof".to_owned(),
+            backend: Arc::new(Jemalloc),
+            endpoints: Endpoint::ALL.to_vec(),
+            custom: Vec::new(),
+            auth: None,
+            audit: audit::global(),
+        }
+    }
+
+    /// Sets the path prefix, e.g. `/debug/pprof`. A trailing `/` is ignored.
+    #[must_use]
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        prefix.trim_end_matches('/').clone_into(&mut self.prefix);
+        self
+    }
+
+    #[must_use]
+    pub fn backend(mut self, backend: Arc<dyn ProfilingBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    #[must_use]
+    pub fn enable(mut self, endpoint: Endpoint) -> Self {
+        if !self.endpoints.contains(&endpoint) {
+            self.endpoints.push(endpoint);
+        }
+        self
+    }
+
+    #[must_use]
+    pub fn disable(mut self, endpoint: Endpoint) -> Self {
+        self.endpoints.retain(|e| *e != endpoint);
+        self
+    }
+
+    /// Checks every request with `auth` before it is handled, e.g. a
+    /// [`Policy`](crate::profiling::auth::Policy).
+    #[must_use]
+    pub fn auth<A: Authorizer + 'static>(mut self, auth: A) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Records mutating requests and heap dumps in `log` instead of
+    /// [`audit::global`].
+    #[must_use]
+    pub fn audit_log(mut self, log: Arc<AuditLog>) -> Self {
+        self.audit = log;
+        self
+    }
+
+    /// Registers a handler for `method` and `path` relative to the prefix.
+    /// Custom handlers take precedence over built-in endpoints. Handlers for
+    /// methods other than GET and HEAD count as mutating and are audited.
+    #[must_use]
+    pub fn handler<F>(mut self, method: Method, path: &str, f: F) -> Self
+    where
+        F: Fn(&dyn ProfilingBackend, &[u8], &HashMap<String, String>) -> HandlerResult
+            + Send
+            + Sync
+            + 'static,
+    {
+        let mutating = method != Method::GET && method != Method::HEAD;
+        self.custom.push(Route {
+            method,
+            path: path.to_owned(),
+            handler: Arc::new(f),
+            description: String::new(),
+            mutating,
+            dumps: false,
+        });
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> Router {
+        let mut routes = self.custom;
+        for endpoint in Endpoint::ALL.into_iter().filter(|e| self.endpoints.contains(e)) {
+            for (method, path, description, handler) in endpoint.routes(&self.prefix, &self.audit) {
+                // POST /symbol only resolves addresses.
+                let mutating = method == Method::POST && path == "/conf";
+                let dumps = path == "/heap" || path == "/ui/profile";
+                routes.push(Route {
+                    method,
+                    path: path.to_owned(),
+                    handler,
+                    description: description.to_owned(),
+                    mutating,
+                    dumps,
+                });
+            }
+        }
+        if self.endpoints.contains(&Endpoint::Index) {
+            let mut index = Route {
+                method: Method::GET,
+                path: "/".to_owned(),
+                handler: Arc::new(|_: &dyn ProfilingBackend, _: &[u8], _: &HashMap<_, _>| {
+                    Err(ErrorResponse::NotFound)
+                }),
+                description: "This page".to_owned(),
+                mutating: false,
+                dumps: false,
+            };
+            let entries: Arc<[index::Entry]> = routes
+                .iter()
+                .chain(Some(&index))
+                .map(|r| index::Entry {
+                    method: r.method.to_string(),
+                    path: format!("{}{}", self.prefix, r.path),
+                    description: r.description.clone(),
+                })
+                .collect();
+            let prefix = self.prefix.clone();
+            index.handler = Arc::new(
+                move |backend: &dyn ProfilingBackend, _: &[u8], params: &HashMap<_, _>| {
+                    get_pprof_index_handler(backend, &prefix, &entries, params)
+                },
+            );
+            routes.push(index);
+        }
+        Router {
+            prefix: self.prefix,
+            backend: self.backend,
+            routes: routes.into(),
+            auth: self.auth,
+            audit: self.audit,
+        }
+    }
+}
+
+impl Default for RouterBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+struct Route {
+    method: Method,
+    path: String,
+    handler: Handler,
+    /// Shown on the index page.
+    description: String,
+    mutating: bool,
+    /// Writes a heap dump. Audited like mutating routes.
+    dumps: bool,
+}
+
+/// Dispatches requests to handlers. Cheap to clone.
+#[derive(Clone)]
+pub struct Router {
+    prefix: String,
+    backend: Arc<dyn ProfilingBackend>,
+    routes: Arc<[Route]>,
+    auth: Option<Arc<dyn Authorizer>>,
+    audit: Arc<AuditLog>,
+}
+
+impl Router {
+    #[must_use]
+    #[inline]
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns method and full path of all routes.
+    pub fn routes(&self) -> impl Iterator<Item = (&Method, String)> + '_ {
+        self.routes.iter().map(|r| (&r.method, format!("{}{}", self.prefix, r.path)))
+    }
+
+    /// Handles a request with the plain `http` types. Address-based checks
+    /// need a [`ClientAddr`] in the request extensions. The response body is
+    /// buffered, see [`Router::handle_streaming`] for large responses.
+    pub fn handle(&self, req: Request<Vec<u8>>) -> http::Result<Response<Vec<u8>>> {
+        let (mut parts, body) = self.handle_streaming(req)?.into_parts();
+        match body.into_bytes() {
+            Ok(body) => {
+                parts.headers.insert(header::CONTENT_LENGTH, body.len().into());
+                Ok(Response::from_parts(parts, body))
+            }
+            Err(e) => {
+                ErrorResponse::Internal(format!("failed to read body: {e}")).to_response(false)
+            }
+        }
+    }
+
+    /// Like [`Router::handle`], but streamed bodies, e.g. heap profiles, are
+    /// read in chunks as the response is sent. Bodies are gzip-compressed if
+    /// the client sends `Accept-Encoding: gzip`.
+    pub fn handle_streaming(&self, req: Request<Vec<u8>>) -> http::Result<Response<Body>> {
+        let path = req.uri().path().strip_prefix(self.prefix.as_str());
+        let matching: Vec<&Route> = self.routes.iter().filter(|r| Some(&*r.path) == path).collect();
+        if let Some(route) = matching.iter().find(|r| r.method == req.method()) {
+            return JeprofHandler::new(self, route).call(req);
+        }
+        let json = wants_json(req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()));
+        if let Err(rejection) = self.authorize(&req) {
+            return ErrorResponse::from(rejection).to_response(json).map(|r| r.map(Body::from));
+        }
+        let resp = if matching.is_empty() {
+            ErrorResponse::NotFound.to_response(json)
+        } else {
+            ErrorResponse::MethodNotAllowed(allowed_methods(&matching)).to_response(json)
+        };
+        resp.map(|resp| resp.map(Body::from))
+    }
+
+    /// Runs the authorizer without handling the request, e.g. to reject it
+    /// before reading the body. Rejections are not audited.
+    pub fn authorize<B>(&self, req: &Request<B>) -> Result<(), Rejection> {
+        let method = req.method();
+        let path = req.uri().path();
+        let authorization = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+        let client = req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip());
+        let route = path
+            .strip_prefix(self.prefix.as_str())
+            .and_then(|p| self.routes.iter().find(|r| r.path == p && r.method == method));
+        match (route, &self.auth) {
+            (Some(route), Some(auth)) => auth.authorize(&AuthRequest {
+                method,
+                path,
+                authorization,
+                client,
+                mutating: route.mutating,
+                dumps: route.dumps,
+            }),
+            (Some(_), None) => Ok(()),
+            (None, auth) => {
+                authorize_unrouted(auth.as_deref(), method, path, authorization, client)
+            }
+        }
+    }
+
+    /// Adds a scope with all routes to an actix-web app, e.g.
+    /// `App::new().configure(|cfg| router.actix_routes(cfg))`.
+    #[cfg(feature = "actix-handlers")]
+    pub fn actix_routes(&self, cfg: &mut actix_web::web::ServiceConfig) {
+        use actix_web::{web, HttpRequest};
+
+        let auth = self.auth.clone();
+        let mut scope =
+            web::scope(&self.prefix).default_service(web::to(move |req: HttpRequest| {
+                let err = actix_authorize_unrouted(auth.as_deref(), &req)
+                    .map_or_else(ErrorResponse::from, |()| ErrorResponse::NotFound);
+                let json = actix_wants_json(&req);
+                async move { err.actix_response(json) }
+            }));
+        let mut paths: Vec<&str> = Vec::new();
+        for route in self.routes.iter() {
+            if !paths.contains(&route.path.as_str()) {
+                paths.push(&route.path);
+            }
+        }
+        for path in paths {
+            let matching: Vec<&Route> = self.routes.iter().filter(|r| r.path == path).collect();
+            let mut resource = web::resource(path);
+            for route in &matching {
+                // actix-web uses http 0.2.
+                let method = actix_web::http::Method::from_bytes(route.method.as_str().as_bytes())
+                    .expect("valid method");
+                resource = resource.route(web::method(method).to(JeprofHandler::new(self, route)));
+            }
+            let allowed = allowed_methods(&matching);
+            let auth = self.auth.clone();
+            resource = resource.default_service(web::to(move |req: HttpRequest| {
+                let err = actix_authorize_unrouted(auth.as_deref(), &req)
+                    .map_or_else(ErrorResponse::from, |()| {
+                        ErrorResponse::MethodNotAllowed(allowed.clone())
+                    });
+                let json = actix_wants_json(&req);
+                async move { err.actix_response(json) }
+            }));
+            scope = scope.service(resource);
+        }
+        cfg.service(scope);
+    }
+}
+
+/// Checks requests matching no route, so that 404 and 405 responses don't
+/// reveal the routes to unauthorized clients.
+fn authorize_unrouted(
+    auth: Option<&dyn Authorizer>,
+    method: &Method,
+    path: &str,
+    authorization: Option<&str>,
+    client: Option<IpAddr>,
+) -> Result<(), Rejection> {
+    auth.map_or(Ok(()), |auth| {
+        auth.authorize(&AuthRequest {
+            method,
+            path,
+            authorization,
+            client,
+            mutating: method != Method::GET && method != Method::HEAD,
+            dumps: false,
+        })
+    })
+}
+
+#[cfg(feature = "actix-handlers")]
+fn actix_authorize_unrouted(
+    auth: Option<&dyn Authorizer>,
+    req: &actix_web::HttpRequest,
+) -> Result<(), Rejection> {
+    // actix-web uses http 0.2.
+    let method =
+        Method::from_bytes(req.method().as_str().as_bytes()).map_err(|_| Rejection::Forbidden)?;
+    let authorization =
+        req.headers().get(actix_web::http::header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let client = req.peer_addr().map(|addr| addr.ip());
+    authorize_unrouted(auth, &method, req.path(), authorization, client)
+}
+
+lazy_static! {
+    static ref DEFAULT_ROUTER: Router = RouterBuilder::new().build();
+}
+
+/// Routes requests to the default [`Router`].
+#[inline]
+pub fn router(req: Request<Vec<u8>>) -> http::Result<Response<Vec<u8>>> {
+    DEFAULT_ROUTER.handle(req)
+}
+
+/// Adds the default [`Router`] to an actix-web app.
 #[cfg(feature = "actix-handlers")]
 #[inline]
 pub fn actix_routes(cfg: &mut actix_web::web::ServiceConfig) {
-    cfg.service(
-        actix_web::web::scope("/pprof")
-            .route("/conf", actix_web::web::get().to(JeprofHandler(get_pprof_conf_handler)))
-            .route("/conf", actix_web::web::post().to(JeprofHandler(post_pprof_conf_handler)))
-            .route("/heap", actix_web::web::get().to(JeprofHandler(get_pprof_heap_handler)))
-            .route("/cmdline", actix_web::web::get().to(JeprofHandler(get_pprof_cmdline_handler)))
-            .route("/symbol", actix_web::web::get().to(JeprofHandler(get_pprof_symbol_handler)))
-            .route("/symbol", actix_web::web::post().to(JeprofHandler(post_pprof_symbol_handler)))
-            .route("/stats", actix_web::web::get().to(JeprofHandler(get_pprof_stats_handler))),
-    );
+    DEFAULT_ROUTER.actix_routes(cfg);
 }
 
-#[derive(Debug)]
-pub struct ErrorResponse(String);
+/// A routing or handler error, answered with the matching status code.
+///
+/// The body is the message as plain text, or `{"status":..,"error":".."}`
+/// if the client prefers `application/json`.
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum ErrorResponse {
+    /// 400, e.g. an unreadable request body.
+    #[error("{0}")]
+    BadRequest(String),
+    /// 401, answered with a `WWW-Authenticate` challenge.
+    #[error("unauthorized")]
+    Unauthorized,
+    /// 403.
+    #[error("forbidden")]
+    Forbidden,
+    /// 404.
+    #[error("not found")]
+    NotFound,
+    /// 405, answered with an `Allow` header listing the given methods.
+    #[error("method not allowed")]
+    MethodNotAllowed(Vec<Method>),
+    /// 413.
+    #[error("request body too large")]
+    PayloadTooLarge,
+    /// 422.
+    #[error("{0}")]
+    InvalidParam(String),
+    /// 500, e.g. mallctl or I/O failures.
+    #[error("{0}")]
+    Internal(String),
+    /// 501, jemalloc was built without profiling support.
+    #[error("jemalloc profiling not available")]
+    NotImplemented,
+    /// 503, profiling is not enabled (`opt.prof`).
+    #[error("jemalloc profiling not enabled")]
+    ProfilingDisabled,
+}
+
+impl ErrorResponse {
+    /// Creates an internal server error.
+    pub fn new<S: Into<String>>(msg: S) -> Self {
+        Self::Internal(msg.into())
+    }
+
+    /// Wraps a backend error, keeping disabled profiling a 503.
+    fn backend(context: &str, err: mallctl::Error) -> Self {
+        match err {
+            mallctl::Error::ProfilingDisabled => Self::ProfilingDisabled,
+            err => Self::Internal(format!("{context}: {err}")),
+        }
+    }
+
+    #[must_use]
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::InvalidParam(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            Self::ProfilingDisabled => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Renders the error as plain text or JSON.
+    pub fn to_response(&self, json: bool) -> http::Result<Response<Vec<u8>>> {
+        let (content_type, body) = self.body(json);
+        let mut resp = Response::builder()
+            .status(self.status())
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len());
+        if let Some((name, value)) = self.header() {
+            resp = resp.header(name, value);
+        }
+        resp.body(body)
+    }
+
+    #[cfg(feature = "actix-handlers")]
+    fn actix_response(&self, json: bool) -> actix_web::HttpResponse {
+        // actix-web uses http 0.2.
+        let status = actix_web::http::StatusCode::from_u16(self.status().as_u16())
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+        let (content_type, body) = self.body(json);
+        let mut resp = actix_web::HttpResponse::build(status);
+        resp.insert_header((actix_web::http::header::CONTENT_TYPE, content_type));
+        if let Some((name, value)) = self.header() {
+            resp.insert_header((name.as_str(), value));
+        }
+        resp.body(body)
+    }
+
+    fn body(&self, json: bool) -> (&'static str, Vec<u8>) {
+        let msg = self.to_string();
+        let msg = msg.trim_end();
+        if json {
+            #[derive(Serialize)]
+            struct Body<'a> {
+                status: u16,
+                error: &'a str,
+            }
+            let body = Body { status: self.status().as_u16(), error: msg };
+            ("application/json", serde_json::to_vec(&body).unwrap_or_default())
+        } else {
+            ("text/plain; charset=UTF-8", format!("{msg}\r\n").into_bytes())
+        }
+    }
+
+    fn header(&self) -> Option<(header::HeaderName, String)> {
+        match self {
+            Self::Unauthorized => Some((header::WWW_AUTHENTICATE, CHALLENGE.to_owned())),
+            Self::MethodNotAllowed(methods) => {
+                let methods: Vec<_> = methods.iter().map(Method::as_str).collect();
+                Some((header::ALLOW, methods.join(", ")))
+            }
+            _ => None,
+        }
+    }
+}
 
-impl fmt::Display for ErrorResponse {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ERROR: {}", self.0)
+impl From<query::Error> for ErrorResponse {
+    fn from(err: query::Error) -> Self {
+        Self::InvalidParam(err.to_string())
     }
 }
 
+impl From<Rejection> for ErrorResponse {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::Unauthorized => Self::Unauthorized,
+            Rejection::Forbidden => Self::Forbidden,
+        }
+    }
+}
+
+/// Used for errors escaping the handlers, which can't see the `Accept`
+/// header, so always plain text.
 #[cfg(feature = "actix-handlers")]
-impl actix_web::ResponseError for ErrorResponse {}
-
-#[derive(Clone, Debug)]
-struct JeprofHandler<F>(F)
-where
-    F: Fn(&[u8], &HashMap<String, String>) -> Result<(Vec<u8>, Option<String>), ErrorResponse>
-        + Clone
-        + 'static;
-
-impl<F> JeprofHandler<F>
-where
-    F: Fn(&[u8], &HashMap<String, String>) -> Result<(Vec<u8>, Option<String>), ErrorResponse>
-        + Clone
-        + 'static,
-{
-    fn call(&self, req: Request<Vec<u8>>) -> http::Result<Response<Vec<u8>>> {
-        let params: HashMap<String, String> = parse_malloc_conf_query(req.uri().query())
-            .iter()
-            .map(|(k, v)| ((*k).to_string(), v.unwrap_or_default().to_string()))
-            .collect();
-        match self.0(req.body(), &params) {
-            Ok((body, Some(content_disposition))) => response_ok_binary(body, &content_disposition),
-            Ok((body, None)) => response_ok(body),
-            Err(err) => response_err(&err.0),
+impl actix_web::ResponseError for ErrorResponse {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::from_u16(self.status().as_u16())
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        self.actix_response(false)
+    }
+}
+
+#[derive(Clone)]
+struct JeprofHandler {
+    backend: Arc<dyn ProfilingBackend>,
+    handler: Handler,
+    auth: Option<Arc<dyn Authorizer>>,
+    method: Method,
+    mutating: bool,
+    dumps: bool,
+    audit: Option<Arc<AuditLog>>,
+    action: String,
+}
+
+impl JeprofHandler {
+    fn new(router: &Router, route: &Route) -> Self {
+        Self {
+            backend: Arc::clone(&router.backend),
+            handler: Arc::clone(&route.handler),
+            auth: router.auth.clone(),
+            method: route.method.clone(),
+            mutating: route.mutating,
+            dumps: route.dumps,
+            audit: (route.mutating || route.dumps).then(|| Arc::clone(&router.audit)),
+            action: format!("{} {}{}", route.method, router.prefix, route.path),
+        }
+    }
+
+    fn record(&self, client: Option<IpAddr>, params: &str, result: Result<(), String>) {
+        if let Some(log) = &self.audit {
+            let caller = client.map_or_else(|| "-".to_owned(), |ip| ip.to_string());
+            log.record(&caller, &self.action, params, result);
+        }
+    }
+
+    fn authorize(
+        &self,
+        path: &str,
+        authorization: Option<&str>,
+        client: Option<IpAddr>,
+    ) -> Result<(), Rejection> {
+        let Some(auth) = &self.auth else {
+            return Ok(());
+        };
+        auth.authorize(&AuthRequest {
+            method: &self.method,
+            path,
+            authorization,
+            client,
+            mutating: self.mutating,
+            dumps: self.dumps,
+        })
+    }
+
+    /// Runs the handler and records the outcome with `recorded` params.
+    fn run(
+        &self,
+        client: Option<IpAddr>,
+        recorded: &str,
+        params: Result<HashMap<String, String>, ErrorResponse>,
+        body: &[u8],
+    ) -> HandlerResult {
+        let result = params.and_then(|params| (self.handler)(&*self.backend, body, &params));
+        self.record(client, recorded, result.as_ref().map(|_| ()).map_err(ToString::to_string));
+        result
+    }
+
+    fn call(&self, req: Request<Vec<u8>>) -> http::Result<Response<Body>> {
+        let authorization = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+        let client = req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip());
+        let accept_json =
+            wants_json(req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()));
+        let query = req.uri().query().unwrap_or_default();
+        let (json, params) = negotiate(query, accept_json);
+        let form = req.headers().get(header::CONTENT_TYPE).map_or(false, |v| is_form(v.as_bytes()));
+        let gzip = body::accepts_gzip(
+            req.headers().get(header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok()),
+        );
+        let recorded = recorded_params(query, form.then(|| req.body().as_slice()));
+        if let Err(rejection) = self.authorize(req.uri().path(), authorization, client) {
+            self.record(client, &recorded, Err(rejection.to_string()));
+            return ErrorResponse::from(rejection).to_response(json).map(|r| r.map(Body::from));
+        }
+
+        match self.run(client, &recorded, params, req.body()) {
+            Ok(reply) => reply.into_response(gzip),
+            Err(err) => err.to_response(json).map(|r| r.map(Body::from)),
         }
     }
 }
 
 #[cfg(feature = "actix-handlers")]
-impl<F>
-    actix_web::Handler<(actix_web::web::Payload, actix_web::web::Query<HashMap<String, String>>)>
-    for JeprofHandler<F>
-where
-    F: Fn(&[u8], &HashMap<String, String>) -> Result<(Vec<u8>, Option<String>), ErrorResponse>
-        + Clone
-        + 'static,
-{
+impl actix_web::Handler<(actix_web::HttpRequest, actix_web::web::Payload)> for JeprofHandler {
     type Output = Result<actix_web::HttpResponse, ErrorResponse>;
     type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Self::Output>>>;
 
     fn call(
         &self,
-        (mut body, query): (
-            actix_web::web::Payload,
-            actix_web::web::Query<HashMap<String, String>>,
-        ),
+        (req, mut body): (actix_web::HttpRequest, actix_web::web::Payload),
     ) -> Self::Future {
         use futures_util::StreamExt as _;
 
-        let f = self.0.clone();
+        let authorization =
+            req.headers().get(actix_web::http::header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+        let client = req.peer_addr().map(|addr| addr.ip());
+        let query = req.query_string().to_owned();
+        let (json, params) = negotiate(&query, actix_wants_json(&req));
+        let form = req
+            .headers()
+            .get(actix_web::http::header::CONTENT_TYPE)
+            .map_or(false, |v| is_form(v.as_bytes()));
+        let gzip = body::accepts_gzip(
+            req.headers()
+                .get(actix_web::http::header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok()),
+        );
+        let rejected = self.authorize(req.path(), authorization, client).err();
+        if let Some(rejection) = rejected {
+            self.record(client, &query, Err(rejection.to_string()));
+        }
+        let this = self.clone();
         Box::pin(async move {
+            if let Some(rejection) = rejected {
+                return Ok(ErrorResponse::from(rejection).actix_response(json));
+            }
             let mut data = Vec::<u8>::new();
             while let Some(item) = body.next().await {
-                data.extend_from_slice(&item.map_err(|e| ErrorResponse(e.to_string()))?);
+                let chunk = item.map_err(|e| ErrorResponse::BadRequest(e.to_string()))?;
+                if data.len() + chunk.len() > MAX_BODY_SIZE {
+                    return Ok(ErrorResponse::PayloadTooLarge.actix_response(json));
+                }
+                data.extend_from_slice(&chunk);
             }
-            f(&data, &query.0).map(|(body, content_disposition)| {
-                let mut resp = actix_web::HttpResponse::Ok();
-                if let Some(filename) = content_disposition {
-                    resp.insert_header(actix_web::http::header::ContentDisposition::attachment(
-                        filename,
-                    ));
+            let recorded = recorded_params(&query, form.then_some(data.as_slice()));
+            Ok(match this.run(client, &recorded, params, &data) {
+                Ok(mut reply) => {
+                    use actix_web::{http::header, web::Bytes};
+
+                    let mut resp = actix_web::HttpResponse::Ok();
+                    resp.insert_header((header::CONTENT_TYPE, reply.content_type));
+                    resp.insert_header((header::VARY, "accept-encoding"));
+                    if reply.encode(gzip) {
+                        resp.insert_header((header::CONTENT_ENCODING, "gzip"));
+                    }
+                    if let Some(filename) = reply.filename {
+                        resp.insert_header(header::ContentDisposition::attachment(filename));
+                    }
+                    match reply.body {
+                        Body::Full(bytes) => resp.body(Bytes::from(bytes)),
+                        // Chunks are read on the worker thread, like the dump itself.
+                        body @ Body::Stream(_) => resp.streaming(futures_util::stream::iter(
+                            body.chunks().map(|chunk| chunk.map(Bytes::from)),
+                        )),
+                    }
                 }
-                resp.body(actix_web::web::Bytes::from(body))
+                Err(err) => err.actix_response(json),
             })
         })
     }
 }
 
+/// Body of GET and POST `/conf` in JSON format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ProfConf {
+    #[serde(rename = "prof.active")]
+    pub active: bool,
+    #[serde(rename = "prof.lg_sample")]
+    pub lg_sample: usize,
+}
+
+fn read_conf(backend: &dyn ProfilingBackend) -> Result<ProfConf, ErrorResponse> {
+    let active =
+        backend.active().map_err(|e| ErrorResponse::backend("failed to read prof.active", e))?;
+    let lg_sample = backend
+        .sample_interval()
+        .map_err(|e| ErrorResponse::backend("failed to read prof.lg_sample", e))?;
+    Ok(ProfConf { active, lg_sample })
+}
+
+/// HTTP handler for GET /pprof/conf.
 #[inline]
 pub fn get_pprof_conf_handler(
+    backend: &dyn ProfilingBackend,
     _body: &[u8],
-    _params: &HashMap<String, String>,
-) -> Result<(Vec<u8>, Option<String>), ErrorResponse> {
-    match mallctl::enabled() {
-        Ok(true) => (),
-        _ => return Err(ErrorResponse("jemalloc profiling not enabled".to_owned())),
-    };
+    params: &HashMap<String, String>,
+) -> HandlerResult {
+    ensure_enabled(backend)?;
 
-    let Ok(state) = mallctl::active() else {
-        return Err(ErrorResponse("failed to read prof.active\r\n".to_owned()));
-    };
-    let Ok(sample) = mallctl::sample_interval() else {
-        return Err(ErrorResponse("failed to read prof.lg_sample\r\n".to_owned()));
-    };
-    let body = format!("prof.active:{state},prof.lg_sample:{sample}\r\n");
-    Ok((body.into_bytes(), None))
+    let conf = read_conf(backend)?;
+    if is_json(params) {
+        return Reply::json(&conf);
+    }
+    let body = format!("prof.active:{},prof.lg_sample:{}\r\n", conf.active, conf.lg_sample);
+    Ok(Reply::text(body))
 }
 
+/// HTTP handler for POST /pprof/conf. Parameters are taken from the query
+/// and from a form-encoded body.
 #[inline]
 pub fn post_pprof_conf_handler(
-    _body: &[u8],
+    backend: &dyn ProfilingBackend,
+    body: &[u8],
     params: &HashMap<String, String>,
-) -> Result<(Vec<u8>, Option<String>), ErrorResponse> {
-    match mallctl::enabled() {
-        Ok(true) => (),
-        _ => return Err(ErrorResponse("jemalloc profiling not enabled\r\n".to_owned())),
-    };
+) -> HandlerResult {
+    ensure_enabled(backend)?;
 
-    for (name, value) in params {
+    // Forms of the index page post their fields in the body.
+    let form = query::parse(Some(&String::from_utf8_lossy(body)))?;
+    if let Some(name) = form.keys().find(|name| params.contains_key(*name)) {
+        return Err(query::Error::DuplicateKey(name.clone()).into());
+    }
+    for (name, value) in params.iter().chain(&form) {
         if let Err(e) = match name.as_str() {
             "prof.reset" => {
                 let sample = value.parse().map_err(|_| {
-                    ErrorResponse(format!("invalid prof.reset value: {value:?}\r\n"))
+                    ErrorResponse::InvalidParam(format!("invalid prof.reset value: {value:?}"))
                 })?;
-                mallctl::reset(Some(sample))
+                backend.reset(Some(sample))
             }
             "prof.active" => {
                 let Some(state) = value.parse().ok() else {
-                    return Err(ErrorResponse(format!("invalid prof.active value: {value:?}\r\n")));
+                    return Err(ErrorResponse::InvalidParam(format!(
+                        "invalid prof.active value: {value:?}"
+                    )));
                 };
-                mallctl::set_active(state)
+                backend.set_active(state)
             }
+            "format" => continue,
             _ => {
-                return Err(ErrorResponse(format!("{name}={value:?} unknown\r\n")));
+                return Err(ErrorResponse::InvalidParam(format!("{name}={value:?} unknown")));
             }
         } {
-            return Err(ErrorResponse(format!("{name}={value:?} failed: {e}\r\n")));
+            return Err(ErrorResponse::backend(&format!("{name}={value:?} failed"), e));
         }
     }
 
-    Ok((b"OK\r\n".to_vec(), None))
+    if is_json(params) {
+        return Reply::json(&read_conf(backend)?);
+    }
+    Ok(Reply::text("OK\r\n"))
 }
 
+/// HTTP handler for GET /pprof/heap. Always in jeprof format.
 #[inline]
 pub fn get_pprof_heap_handler(
+    backend: &dyn ProfilingBackend,
     _body: &[u8],
     _params: &HashMap<String, String>,
-) -> Result<(Vec<u8>, Option<String>), ErrorResponse> {
-    match mallctl::enabled() {
-        Ok(true) => (),
-        _ => return Err(ErrorResponse("jemalloc profiling not enabled\r\n".to_owned())),
-    };
+) -> HandlerResult {
+    ensure_enabled(backend)?;
 
-    let Ok(f) = tempfile::Builder::new().prefix("jemalloc.").suffix(".prof").tempfile() else {
-        return Err(ErrorResponse("cannot create temporary file for profile dump\r\n".to_owned()));
-    };
+    let profile =
+        backend.dump_reader().map_err(|e| ErrorResponse::backend("failed to dump profile", e))?;
 
-    let Ok(profile) = mallctl::dump(f.path().to_str()) else {
-        return Err(ErrorResponse("failed to dump profile\r\n".to_owned()));
-    };
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let filename = format!("jemalloc.{}.{secs}.prof", std::process::id());
+    Ok(Reply::attachment(Body::Stream(profile), filename))
+}
 
-    let filename = f.path().file_name().expect("proper filename from tempfile");
-    Ok((profile.expect("profile not None"), Some(filename.to_string_lossy().to_string())))
+/// HTTP handler for GET /pprof/ui/profile. Always in JSON format.
+#[inline]
+pub fn get_pprof_ui_profile_handler(
+    backend: &dyn ProfilingBackend,
+    _body: &[u8],
+    _params: &HashMap<String, String>,
+) -> HandlerResult {
+    ensure_enabled(backend)?;
+
+    let profile =
+        backend.dump().map_err(|e| ErrorResponse::backend("failed to dump profile", e))?;
+    let profile = heap::parse(&profile)
+        .map_err(|e| ErrorResponse::Internal(format!("failed to parse profile: {e}")))?;
+    Reply::json(&profile.symbolize())
+}
+
+/// The dashboard with the router prefix filled in.
+fn dashboard_page(prefix: &str) -> Vec<u8> {
+    // Safe inside <script>: no `</` in the literal.
+    let prefix = serde_json::to_string(prefix).unwrap_or_default().replace("</", "<\\/");
+    include_str!("dashboard.html").replacen("__PREFIX__", &prefix, 1).into_bytes()
 }
 
 /// HTTP handler for GET /pprof/cmdline.
 #[inline]
 pub fn get_pprof_cmdline_handler(
+    _backend: &dyn ProfilingBackend,
     _body: &[u8],
-    _params: &HashMap<String, String>,
-) -> Result<(Vec<u8>, Option<String>), ErrorResponse> {
+    params: &HashMap<String, String>,
+) -> HandlerResult {
+    if is_json(params) {
+        return Reply::json(&json!({ "args": env::args().collect::<Vec<_>>() }));
+    }
     let mut body = String::new();
     for arg in env::args() {
         body.push_str(arg.as_str());
         body.push_str("\r\n");
     }
-    Ok((body.into_bytes(), None))
+    Ok(Reply::text(body))
 }
 
 /// HTTP handler for GET /pprof/symbol.
 #[inline]
 pub fn get_pprof_symbol_handler(
+    _backend: &dyn ProfilingBackend,
     _body: &[u8],
-    _params: &HashMap<String, String>,
-) -> Result<(Vec<u8>, Option<String>), ErrorResponse> {
+    params: &HashMap<String, String>,
+) -> HandlerResult {
     // TODO: any quick way to check if binary is stripped?
-    let body = b"num_symbols: 1\r\n";
-    Ok((body.to_vec(), None))
+    if is_json(params) {
+        return Reply::json(&json!({ "num_symbols": 1 }));
+    }
+    Ok(Reply::text("num_symbols: 1\r\n"))
 }
 
 /// HTTP handler for POST /pprof/symbol.
 #[inline]
 pub fn post_pprof_symbol_handler(
+    _backend: &dyn ProfilingBackend,
     body: &[u8],
-    _params: &HashMap<String, String>,
-) -> Result<(Vec<u8>, Option<String>), ErrorResponse> {
+    params: &HashMap<String, String>,
+) -> HandlerResult {
     fn lookup_symbol(addr: u64) -> Option<String> {
         let mut s: Option<String> = None;
         backtrace::resolve(addr as *mut _, |symbol| {
@@ -258,63 +1114,771 @@ pub fn post_pprof_symbol_handler(
         .map(|addr| (addr, lookup_symbol(addr)))
         .filter_map(|(addr, sym)| sym.map(|sym| (addr, sym)));
 
+    if is_json(params) {
+        let symbols: Vec<_> = addrs
+            .map(|(addr, sym)| json!({ "address": format!("{addr:#x}"), "name": sym }))
+            .collect();
+        return Reply::json(&json!({ "symbols": symbols }));
+    }
     let mut body = String::new();
     for (addr, sym) in addrs {
         body.push_str(format!("{addr:#x}\t{sym}\r\n").as_str());
     }
 
-    Ok((body.into_bytes(), None))
+    Ok(Reply::text(body))
 }
 
 /// HTTP handler for GET /pprof/stats.
 #[inline]
 pub fn get_pprof_stats_handler(
+    backend: &dyn ProfilingBackend,
+    _body: &[u8],
+    params: &HashMap<String, String>,
+) -> HandlerResult {
+    if is_json(params) {
+        let json =
+            backend.stats_json().map_err(|e| ErrorResponse::backend("failed to print stats", e))?;
+        let stats = stats::parse(&json)
+            .map_err(|e| ErrorResponse::Internal(format!("failed to parse stats: {e}")))?;
+        return Reply::json(&stats);
+    }
+    let stats =
+        backend.stats_reader().map_err(|e| ErrorResponse::backend("failed to print stats", e))?;
+    Ok(Reply { body: Body::Stream(stats), ..Reply::text(Vec::new()) })
+}
+
+/// HTTP handler for GET /pprof/metrics. Always in Prometheus text format.
+#[inline]
+pub fn get_pprof_metrics_handler(
+    backend: &dyn ProfilingBackend,
     _body: &[u8],
     _params: &HashMap<String, String>,
-) -> Result<(Vec<u8>, Option<String>), ErrorResponse> {
-    let body = match mallctl::stats() {
+) -> HandlerResult {
+    let body = match backend.metrics() {
         Ok(body) => body,
-        Err(e) => return Err(ErrorResponse(format!("failed to print stats: {e}\r\n"))),
+        Err(e) => return Err(ErrorResponse::Internal(format!("failed to collect metrics: {e}"))),
     };
-    Ok((body, None))
-}
-
-fn parse_malloc_conf_query(query: Option<&str>) -> Vec<(&str, Option<&str>)> {
-    query
-        .map(|q| {
-            q.split(',')
-                .map(|kv| kv.splitn(2, ':').collect::<Vec<_>>())
-                .map(|v| match v.len() {
-                    1 => (v[0], None),
-                    2 => (v[0], Some(v[1])),
-                    _ => unreachable!(),
+    Ok(Reply::text(body))
+}
+
+/// HTTP handler for GET /pprof/, lists `routes`.
+pub fn get_pprof_index_handler(
+    backend: &dyn ProfilingBackend,
+    prefix: &str,
+    routes: &[index::Entry],
+    params: &HashMap<String, String>,
+) -> HandlerResult {
+    let state = index::State::read(backend);
+    if is_json(params) {
+        return Reply::json(&index::Index { profiling: state, routes });
+    }
+    Ok(Reply::html(index::html(prefix, state, routes)))
+}
+
+/// HTTP handler for GET /pprof/audit.
+pub fn get_pprof_audit_handler(log: &AuditLog, params: &HashMap<String, String>) -> HandlerResult {
+    if is_json(params) {
+        let entries: Vec<_> = log
+            .entries()
+            .into_iter()
+            .map(|e| {
+                let time = e.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                json!({
+                    "time_ms": u64::try_from(time.as_millis()).unwrap_or(u64::MAX),
+                    "caller": e.caller,
+                    "action": e.action,
+                    "params": e.params,
+                    "error": e.result.err(),
                 })
-                .collect()
-        })
-        .unwrap_or_default()
+            })
+            .collect();
+        return Reply::json(&json!({ "entries": entries }));
+    }
+    let mut body = String::new();
+    for entry in log.entries() {
+        body.push_str(&entry.to_string());
+        body.push_str("\r\n");
+    }
+    Ok(Reply::text(body))
+}
+
+/// HTTP handler for GET /pprof/cgroup.
+#[inline]
+pub fn get_pprof_cgroup_handler(
+    backend: &dyn ProfilingBackend,
+    _body: &[u8],
+    params: &HashMap<String, String>,
+) -> HandlerResult {
+    let stats = match backend.cgroup_memory() {
+        Ok(stats) => stats,
+        Err(e) => return Err(ErrorResponse::Internal(format!("failed to read cgroup: {e}"))),
+    };
+    if is_json(params) {
+        return Reply::json(&stats);
+    }
+    let mut body = String::new();
+    for line in stats.to_string().lines() {
+        body.push_str(line);
+        body.push_str("\r\n");
+    }
+    Ok(Reply::text(body))
 }
 
-fn response_ok(body: Vec<u8>) -> http::Result<Response<Vec<u8>>> {
-    Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "text/plain; charset=UTF-8")
-        .header(header::CONTENT_LENGTH, body.len())
-        .body(body)
+// opt.prof can't be read if jemalloc was built without profiling.
+fn ensure_enabled(backend: &dyn ProfilingBackend) -> Result<(), ErrorResponse> {
+    match backend.enabled() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ErrorResponse::ProfilingDisabled),
+        Err(_) => Err(ErrorResponse::NotImplemented),
+    }
 }
 
-fn response_ok_binary(body: Vec<u8>, filename: &str) -> http::Result<Response<Vec<u8>>> {
-    Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/octet-stream")
-        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\""))
-        .header(header::CONTENT_LENGTH, body.len())
-        .body(body)
+fn is_form(content_type: &[u8]) -> bool {
+    content_type.starts_with(b"application/x-www-form-urlencoded")
 }
 
-fn response_err(msg: &str) -> http::Result<Response<Vec<u8>>> {
-    Response::builder()
-        .status(StatusCode::BAD_REQUEST)
-        .header(header::CONTENT_TYPE, "text/plain; charset=UTF-8")
-        .header(header::CONTENT_LENGTH, msg.len())
-        .body(msg.as_bytes().to_owned())
+/// The query, followed by the body if it holds form fields.
+fn recorded_params<'a>(query: &'a str, form: Option<&[u8]>) -> Cow<'a, str> {
+    match form.filter(|body| !body.is_empty()) {
+        Some(body) if query.is_empty() => String::from_utf8_lossy(body).into_owned().into(),
+        Some(body) => format!("{query}&{}", String::from_utf8_lossy(body)).into(),
+        None => query.into(),
+    }
+}
+
+/// Parses the query and picks the response format: `?format=json` or
+/// `?format=text` take precedence over the `Accept` header. The choice is
+/// passed on to handlers as the `format` parameter.
+fn negotiate(
+    query: &str,
+    accept_json: bool,
+) -> (bool, Result<HashMap<String, String>, ErrorResponse>) {
+    let mut params = match query::parse(Some(query)) {
+        Ok(params) => params,
+        Err(e) => return (accept_json, Err(e.into())),
+    };
+    let json = match params.get("format").map(String::as_str) {
+        None => accept_json,
+        Some("json") => true,
+        Some("text") => false,
+        Some(other) => {
+            let err = ErrorResponse::InvalidParam(format!("invalid format: {other:?}"));
+            return (accept_json, Err(err));
+        }
+    };
+    params.insert("format".to_owned(), if json { "json" } else { "text" }.to_owned());
+    (json, Ok(params))
+}
+
+fn is_json(params: &HashMap<String, String>) -> bool {
+    params.get("format").map(String::as_str) == Some("json")
+}
+
+fn allowed_methods(routes: &[&Route]) -> Vec<Method> {
+    let mut methods: Vec<Method> = Vec::new();
+    for route in routes {
+        if !methods.contains(&route.method) {
+            methods.push(route.method.clone());
+        }
+    }
+    methods
+}
+
+/// Whether an `Accept` header ranks `application/json` above plain text.
+fn wants_json(accept: Option<&str>) -> bool {
+    let (mut json, mut text) = (0.0_f32, 0.0_f32);
+    for range in accept.unwrap_or_default().split(',') {
+        let mut parts = range.split(';').map(str::trim);
+        let media = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0_f32);
+        match media.as_str() {
+            "application/json" => json = json.max(q),
+            "text/plain" | "text/*" | "*/*" => text = text.max(q),
+            _ => (),
+        }
+    }
+    json > text
+}
+
+#[cfg(feature = "actix-handlers")]
+fn actix_wants_json(req: &actix_web::HttpRequest) -> bool {
+    wants_json(req.headers().get(actix_web::http::header::ACCEPT).and_then(|v| v.to_str().ok()))
+}
+
+const CHALLENGE: &str = "Bearer realm=\"pprof\"";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiling::{
+        audit::AuditLog,
+        auth::Policy,
+        backend::{MockBackend, MockState},
+    };
+
+    fn request(router: &Router, method: Method, uri: &str, body: &[u8]) -> Response<Vec<u8>> {
+        let req = Request::builder().method(method).uri(uri).body(body.to_vec()).expect("request");
+        router.handle(req).expect("response")
+    }
+
+    fn call(mock: &Arc<MockBackend>, method: Method, uri: &str, body: &[u8]) -> Response<Vec<u8>> {
+        let router = RouterBuilder::new().backend(Arc::clone(mock) as _).build();
+        request(&router, method, uri, body)
+    }
+
+    #[test]
+    fn test_conf() {
+        let mock = Arc::new(MockBackend::default());
+        let resp = call(&mock, Method::GET, "/pprof/conf", b"");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(b"prof.active:false,prof.lg_sample:19\r\n", resp.body().as_slice());
+
+        let resp = call(&mock, Method::POST, "/pprof/conf?prof.active:true,prof.reset:10", b"");
+        assert_eq!(StatusCode::OK, resp.status());
+        let state = mock.state();
+        assert_eq!((true, 10, 1), (state.active, state.lg_sample, state.resets));
+
+        let resp = call(&mock, Method::POST, "/pprof/conf?prof.active:yes", b"");
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, resp.status());
+        assert_eq!(b"invalid prof.active value: \"yes\"\r\n", resp.body().as_slice());
+        let resp = call(&mock, Method::POST, "/pprof/conf?prof.foo:1", b"");
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, resp.status());
+
+        let resp =
+            call(&mock, Method::POST, "/pprof/conf?prof.active=false&prof.reset=%31%32", b"");
+        assert_eq!(StatusCode::OK, resp.status());
+        let state = mock.state();
+        assert_eq!((false, 12, 2), (state.active, state.lg_sample, state.resets));
+        let resp = call(&mock, Method::POST, "/pprof/conf?prof.active:true&prof.active=true", b"");
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, resp.status());
+        assert_eq!(b"duplicate parameter: prof.active\r\n", resp.body().as_slice());
+        assert!(!mock.state().active);
+    }
+
+    #[test]
+    fn test_heap() {
+        let mock = Arc::new(MockBackend::default());
+        let resp = call(&mock, Method::GET, "/pprof/heap", b"");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(MockState::default().profile, *resp.body());
+        let disposition = resp.headers()[header::CONTENT_DISPOSITION].to_str().expect("str");
+        assert!(disposition.starts_with("attachment; filename=\"jemalloc."), "{disposition}");
+        assert_eq!(1, mock.state().dumps);
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Read as _;
+
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(data).read_to_end(&mut out).expect("gunzip");
+        out
+    }
+
+    fn large_profile() -> Vec<u8> {
+        let mut profile = b"heap_v2/524288\n".to_vec();
+        for i in 0..1000 {
+            profile.extend_from_slice(format!("@ {i:#x}\n  t*: 1: 64 [0: 0]\n").as_bytes());
+        }
+        profile
+    }
+
+    #[test]
+    fn test_gzip() {
+        let profile = large_profile();
+        let mock = Arc::new(MockBackend::new(MockState {
+            profile: profile.clone(),
+            ..MockState::default()
+        }));
+        let router = RouterBuilder::new().backend(mock).build();
+        let get = |uri: &str, encoding: &str| {
+            Request::get(uri)
+                .header(header::ACCEPT_ENCODING, encoding)
+                .body(Vec::new())
+                .expect("request")
+        };
+
+        let resp = router.handle(get("/pprof/heap", "br, gzip")).expect("response");
+        assert_eq!("gzip", resp.headers()[header::CONTENT_ENCODING]);
+        assert_eq!(resp.body().len().to_string(), resp.headers()[header::CONTENT_LENGTH]);
+        assert!(resp.body().len() < profile.len());
+        assert_eq!(profile, gunzip(resp.body()));
+
+        let resp = router.handle_streaming(get("/pprof/heap", "identity")).expect("response");
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(!resp.headers().contains_key(header::CONTENT_LENGTH));
+        let chunks: Vec<_> = resp.into_body().chunks().map(|c| c.expect("chunk")).collect();
+        assert_eq!(profile, chunks.concat());
+
+        let resp = router.handle_streaming(get("/pprof/heap", "gzip")).expect("response");
+        let chunks: Vec<_> = resp.into_body().chunks().map(|c| c.expect("chunk")).collect();
+        assert_eq!(profile, gunzip(&chunks.concat()));
+
+        // Too small to bother.
+        let resp = router.handle(get("/pprof/conf", "gzip")).expect("response");
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(b"prof.active:false,prof.lg_sample:19\r\n", resp.body().as_slice());
+        let resp = router.handle(get("/pprof/nope", "gzip")).expect("response");
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert_eq!(b"not found\r\n", resp.body().as_slice());
+    }
+
+    #[test]
+    fn test_profiling_disabled() {
+        let mock = Arc::new(MockBackend::new(MockState { enabled: false, ..Default::default() }));
+        for (method, uri) in [
+            (Method::GET, "/pprof/conf"),
+            (Method::POST, "/pprof/conf"),
+            (Method::GET, "/pprof/heap"),
+        ] {
+            let resp = call(&mock, method, uri, b"");
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+            assert_eq!(b"jemalloc profiling not enabled\r\n", resp.body().as_slice());
+        }
+        assert_eq!(0, mock.state().dumps);
+    }
+
+    #[test]
+    fn test_other_endpoints() {
+        let mock = Arc::new(MockBackend::default());
+        let resp = call(&mock, Method::GET, "/pprof/stats", b"");
+        assert_eq!(MockState::default().stats, *resp.body());
+
+        let resp = call(&mock, Method::GET, "/pprof/cmdline", b"");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(resp.body().ends_with(b"\r\n"));
+
+        let resp = call(&mock, Method::GET, "/pprof/symbol", b"");
+        assert_eq!(b"num_symbols: 1\r\n", resp.body().as_slice());
+        let addr = format!("{:#x}", test_other_endpoints as *const () as usize + 1);
+        let resp = call(&mock, Method::POST, "/pprof/symbol", addr.as_bytes());
+        let body = String::from_utf8_lossy(resp.body());
+        assert!(body.starts_with(&format!("{addr}\t")), "{body}");
+
+        let resp = call(&mock, Method::GET, "/pprof/metrics", b"");
+        assert_eq!(MockState::default().metrics.as_bytes(), resp.body().as_slice());
+
+        let resp = call(&mock, Method::GET, "/pprof/cgroup", b"");
+        let body = String::from_utf8_lossy(resp.body());
+        assert!(body.contains("1048576"), "{body}");
+        let mock = Arc::new(MockBackend::new(MockState { cgroup: None, ..Default::default() }));
+        let resp = call(&mock, Method::GET, "/pprof/cgroup", b"");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, resp.status());
+
+        let resp = call(&mock, Method::GET, "/pprof/nope", b"");
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[test]
+    fn test_json() {
+        let mock = Arc::new(MockBackend::default());
+        let router = RouterBuilder::new().backend(mock).build();
+        let get_json = |method: Method, uri: &str, body: &[u8]| {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::ACCEPT, "application/json")
+                .body(body.to_vec())
+                .expect("request");
+            let resp = router.handle(req).expect("response");
+            assert_eq!("application/json", resp.headers()[header::CONTENT_TYPE], "{uri}");
+            serde_json::from_slice::<serde_json::Value>(resp.body()).expect("json")
+        };
+
+        let conf = get_json(Method::GET, "/pprof/conf", b"");
+        assert_eq!(json!({"prof.active": false, "prof.lg_sample": 19}), conf);
+        let conf = get_json(Method::POST, "/pprof/conf?prof.active=true", b"");
+        assert_eq!(json!({"prof.active": true, "prof.lg_sample": 19}), conf);
+        let stats = get_json(Method::GET, "/pprof/stats", b"");
+        assert_eq!(json!("mock"), stats["version"]);
+        assert_eq!(json!(4), stats["stats"]["resident"]);
+        assert_eq!(json!(1), stats["stats.arenas"]["merged"]["small"]["nmalloc"]);
+        assert_eq!(json!({"num_symbols": 1}), get_json(Method::GET, "/pprof/symbol", b""));
+        let addr = format!("{:#x}", test_json as *const () as usize + 1);
+        let symbols = get_json(Method::POST, "/pprof/symbol", addr.as_bytes());
+        assert_eq!(json!(addr), symbols["symbols"][0]["address"]);
+        assert!(get_json(Method::GET, "/pprof/cmdline", b"")["args"].is_array());
+        assert!(get_json(Method::GET, "/pprof/audit", b"")["entries"].is_array());
+
+        let resp = request(&router, Method::GET, "/pprof/conf?format=json", b"");
+        assert_eq!(br#"{"prof.active":true,"prof.lg_sample":19}"#, resp.body().as_slice());
+        let req = Request::get("/pprof/conf?format=text")
+            .header(header::ACCEPT, "application/json")
+            .body(Vec::new())
+            .expect("request");
+        let resp = router.handle(req).expect("response");
+        assert_eq!(b"prof.active:true,prof.lg_sample:19\r\n", resp.body().as_slice());
+        let resp = request(&router, Method::GET, "/pprof/conf?format=xml", b"");
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, resp.status());
+        let resp = request(&router, Method::GET, "/pprof/heap?format=json", b"");
+        assert_eq!("application/octet-stream", resp.headers()[header::CONTENT_TYPE]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mock = Arc::new(MockBackend::default());
+        let resp = call(&mock, Method::DELETE, "/pprof/conf", b"");
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, resp.status());
+        assert_eq!("GET, POST", resp.headers()[header::ALLOW]);
+        assert_eq!(b"method not allowed\r\n", resp.body().as_slice());
+
+        let router = RouterBuilder::new().backend(mock).build();
+        let req = Request::post("/pprof/conf?prof.reset:x")
+            .header(header::ACCEPT, "text/plain;q=0.5, application/json")
+            .body(Vec::new())
+            .expect("request");
+        let resp = router.handle(req).expect("response");
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, resp.status());
+        assert_eq!("application/json", resp.headers()[header::CONTENT_TYPE]);
+        assert_eq!(
+            br#"{"status":422,"error":"invalid prof.reset value: \"x\""}"#,
+            resp.body().as_slice()
+        );
+
+        assert!(!wants_json(None));
+        assert!(!wants_json(Some("*/*")));
+        assert!(!wants_json(Some("application/json;q=0.5, text/*")));
+        assert!(wants_json(Some("application/json")));
+        assert_eq!(StatusCode::NOT_IMPLEMENTED, ErrorResponse::NotImplemented.status());
+        let err = ErrorResponse::backend("dump", mallctl::Error::MallctlCode(14));
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, err.status());
+    }
+
+    #[test]
+    fn test_router_builder() {
+        let router = RouterBuilder::new()
+            .prefix("/debug/pprof/")
+            .backend(Arc::new(MockBackend::default()))
+            .disable(Endpoint::Cmdline)
+            .handler(Method::GET, "/build", |_, _, params| {
+                let name = params.get("name").map_or("", String::as_str);
+                Ok(Reply::text(format!("build {name}\r\n")))
+            })
+            .handler(Method::GET, "/stats", |_, _, _| Err(ErrorResponse::new("overridden\r\n")))
+            .build();
+
+        let resp = request(&router, Method::GET, "/debug/pprof/build?name:x", b"");
+        assert_eq!(b"build x\r\n", resp.body().as_slice());
+        let resp = request(&router, Method::GET, "/debug/pprof/stats", b"");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, resp.status());
+        assert_eq!(b"overridden\r\n", resp.body().as_slice());
+        let resp = request(&router, Method::GET, "/debug/pprof/conf", b"");
+        assert_eq!(StatusCode::OK, resp.status());
+        let resp = request(&router, Method::GET, "/debug/pprof/cmdline", b"");
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let resp = request(&router, Method::GET, "/pprof/conf", b"");
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+
+        let paths: Vec<_> = router.routes().map(|(m, p)| format!("{m} {p}")).collect();
+        assert_eq!("GET /debug/pprof/build", paths[0]);
+        assert!(paths.contains(&"POST /debug/pprof/symbol".to_owned()));
+        assert!(!paths.iter().any(|r| r.ends_with("/cmdline")));
+    }
+
+    #[test]
+    fn test_index() {
+        let mock = Arc::new(MockBackend::default());
+        let log = Arc::new(AuditLog::default());
+        let router = RouterBuilder::new()
+            .backend(mock.clone())
+            .audit_log(log.clone())
+            .disable(Endpoint::Cmdline)
+            .handler(Method::GET, "/build", |_, _, _| Ok(Reply::text("v1\r\n")))
+            .build();
+        let resp = request(&router, Method::GET, "/pprof/", b"");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("text/html; charset=UTF-8", resp.headers()[header::CONTENT_TYPE]);
+        let html = String::from_utf8_lossy(resp.body());
+        assert!(html.contains("enabled, inactive, lg_sample 19"), "{html}");
+        assert!(html.contains("<a href=\"/pprof/build\">/pprof/build</a>"), "{html}");
+        assert!(html.contains("<td>POST</td><td>/pprof/conf</td>"), "{html}");
+        assert!(!html.contains("/pprof/cmdline"), "{html}");
+
+        let resp = request(&router, Method::GET, "/pprof/?format=json", b"");
+        let index: serde_json::Value = serde_json::from_slice(resp.body()).expect("json");
+        assert_eq!(json!({"enabled": true, "active": false, "lg_sample": 19}), index["profiling"]);
+        let paths: Vec<_> = router.routes().map(|(m, p)| (m.to_string(), p)).collect();
+        let listed: Vec<_> = index["routes"]
+            .as_array()
+            .expect("routes")
+            .iter()
+            .map(|r| {
+                (
+                    r["method"].as_str().unwrap_or("").to_owned(),
+                    r["path"].as_str().unwrap_or("").to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(paths, listed);
+
+        let req = Request::post("/pprof/conf?prof.active=true")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(b"prof.reset=12".to_vec())
+            .expect("request");
+        assert_eq!(StatusCode::OK, router.handle(req).expect("response").status());
+        assert_eq!((true, 12), (mock.state().active, mock.state().lg_sample));
+        assert_eq!("prof.active=true&prof.reset=12", log.entries()[0].params);
+        let resp = request(&router, Method::POST, "/pprof/conf?prof.active=true", b"prof.active=1");
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, resp.status());
+    }
+
+    #[test]
+    fn test_dashboard() {
+        let mock = Arc::new(MockBackend::new(MockState {
+            profile: b"heap_v2/1\n  t*: 3: 48 [0: 0]\n@ 0x10 0x20\n  t*: 3: 48 [0: 0]\n".to_vec(),
+            ..MockState::default()
+        }));
+        let router = RouterBuilder::new().prefix("/debug/pprof").backend(mock.clone()).build();
+        let resp = request(&router, Method::GET, "/debug/pprof/ui", b"");
+        assert_eq!("text/html; charset=UTF-8", resp.headers()[header::CONTENT_TYPE]);
+        let html = String::from_utf8_lossy(resp.body());
+        assert!(html.contains(r#"const PREFIX = "/debug/pprof";"#), "{html}");
+        // Self-contained, nothing loaded from elsewhere.
+        assert!(!html.contains("<script src") && !html.contains("<link"), "{html}");
+
+        let resp = request(&router, Method::GET, "/debug/pprof/ui/profile", b"");
+        assert_eq!(StatusCode::OK, resp.status());
+        let profile: serde_json::Value = serde_json::from_slice(resp.body()).expect("json");
+        assert_eq!(json!(1), profile["sample_period"]);
+        assert_eq!(json!([{"frames": [0, 1], "objects": 3, "bytes": 48}]), profile["stacks"]);
+        assert_eq!(json!(["0x10", "0x1f"]), profile["functions"]);
+        assert_eq!(1, mock.state().dumps);
+
+        let resp = request(&router, Method::GET, "/debug/pprof/", b"");
+        assert!(String::from_utf8_lossy(resp.body()).contains("href=\"/debug/pprof/ui\""));
+        let html = String::from_utf8(dashboard_page("</script>")).expect("utf-8");
+        assert!(html.contains(r#"const PREFIX = "<\/script>";"#), "{html}");
+    }
+
+    #[test]
+    fn test_auth() {
+        let mock = Arc::new(MockBackend::default());
+        let router = RouterBuilder::new()
+            .backend(mock.clone())
+            .auth(Policy::new().bearer_token("s3cret").loopback_only().read_only())
+            .build();
+        let request = |method: Method, uri: &str, token: Option<&str>, client: &str| {
+            let mut req = Request::builder().method(method).uri(uri);
+            if let Some(token) = token {
+                req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            let mut req = req.body(Vec::new()).expect("request");
+            if let Ok(addr) = client.parse() {
+                req.extensions_mut().insert(ClientAddr(addr));
+            }
+            router.handle(req).expect("response")
+        };
+
+        let resp = request(Method::GET, "/pprof/conf", Some("s3cret"), "127.0.0.1:1234");
+        assert_eq!(StatusCode::OK, resp.status());
+        let resp = request(Method::GET, "/pprof/conf", None, "127.0.0.1:1234");
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert_eq!("Bearer realm=\"pprof\"", resp.headers()[header::WWW_AUTHENTICATE]);
+        let resp = request(Method::GET, "/pprof/heap", Some("wrong"), "127.0.0.1:1234");
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        let resp = request(Method::GET, "/pprof/conf", Some("s3cret"), "10.0.0.1:1234");
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        let resp = request(Method::GET, "/pprof/conf", Some("s3cret"), "");
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        let resp = request(Method::POST, "/pprof/conf?prof.active:true", Some("s3cret"), "[::1]:1");
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        let resp = request(Method::GET, "/pprof/heap", Some("s3cret"), "[::1]:1");
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        let resp = request(Method::GET, "/pprof/nope", None, "127.0.0.1:1234");
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        let resp = request(Method::PUT, "/pprof/conf", None, "127.0.0.1:1234");
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert!(!resp.headers().contains_key(header::ALLOW));
+        let resp = request(Method::GET, "/pprof/nope", Some("s3cret"), "127.0.0.1:1234");
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let resp = request(Method::DELETE, "/pprof/conf", Some("s3cret"), "10.0.0.1:1234");
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        let resp = request(Method::POST, "/pprof/symbol", Some("s3cret"), "[::1]:1");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!((false, 0), (mock.state().active, mock.state().dumps));
+    }
+
+    #[test]
+    fn test_audit() {
+        let log = Arc::new(AuditLog::new(16));
+        let router = RouterBuilder::new()
+            .backend(Arc::new(MockBackend::default()))
+            .audit_log(Arc::clone(&log))
+            .auth(Policy::new().bearer_token("s3cret"))
+            .build();
+        let request = |method: Method, uri: &str, token: &str| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Vec::new())
+                .expect("request");
+            req.extensions_mut().insert(ClientAddr("192.0.2.1:4321".parse().expect("addr")));
+            router.handle(req).expect("response")
+        };
+
+        request(Method::POST, "/pprof/conf?prof.active:true", "s3cret");
+        request(Method::POST, "/pprof/conf?prof.active:yes", "s3cret");
+        request(Method::POST, "/pprof/conf?prof.active:true", "guess");
+        request(Method::GET, "/pprof/heap", "s3cret");
+        request(Method::GET, "/pprof/conf", "s3cret");
+
+        let entries = log.entries();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.caller.as_str(), e.action.as_str(), e.params.as_str(), e.result.is_ok()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("192.0.2.1", "POST /pprof/conf", "prof.active:true", true),
+                ("192.0.2.1", "POST /pprof/conf", "prof.active:yes", false),
+                ("192.0.2.1", "POST /pprof/conf", "prof.active:true", false),
+                ("192.0.2.1", "GET /pprof/heap", "", true),
+            ],
+            summary
+        );
+        assert_eq!(Err("unauthorized".to_owned()), entries[2].result);
+
+        let resp = request(Method::GET, "/pprof/audit", "s3cret");
+        let body = String::from_utf8_lossy(resp.body());
+        assert_eq!(4, body.lines().count(), "{body}");
+        assert!(body.contains(" 192.0.2.1 POST /pprof/conf \"prof.active:true\" ok\r\n"), "{body}");
+    }
+
+    #[cfg(feature = "actix-handlers")]
+    #[actix_web::test]
+    async fn test_actix_auth() {
+        use actix_web::{test, App};
+
+        let mock = Arc::new(MockBackend::default());
+        let router = RouterBuilder::new()
+            .backend(mock.clone())
+            .auth(Policy::new().bearer_token("s3cret").read_only())
+            .build();
+        let app = test::init_service(App::new().configure(|cfg| router.actix_routes(cfg))).await;
+
+        let req = test::TestRequest::get().uri("/pprof/heap").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(actix_web::http::StatusCode::UNAUTHORIZED, resp.status());
+        assert!(resp.headers().contains_key(actix_web::http::header::WWW_AUTHENTICATE));
+        let req = test::TestRequest::get().uri("/pprof/nope").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(actix_web::http::StatusCode::UNAUTHORIZED, resp.status());
+        let req = test::TestRequest::put().uri("/pprof/conf").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(actix_web::http::StatusCode::UNAUTHORIZED, resp.status());
+        let req = test::TestRequest::get()
+            .uri("/pprof/nope")
+            .insert_header(("Authorization", "Bearer s3cret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(actix_web::http::StatusCode::NOT_FOUND, resp.status());
+        let req = test::TestRequest::post()
+            .uri("/pprof/conf?prof.active:true")
+            .insert_header(("Authorization", "Bearer s3cret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(actix_web::http::StatusCode::FORBIDDEN, resp.status());
+        let req = test::TestRequest::get()
+            .uri("/pprof/heap")
+            .insert_header(("Authorization", "Bearer s3cret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(actix_web::http::StatusCode::FORBIDDEN, resp.status());
+        let req = test::TestRequest::get()
+            .uri("/pprof/conf")
+            .insert_header(("Authorization", "Bearer s3cret"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!((false, 0), (mock.state().active, mock.state().dumps));
+    }
+
+    #[cfg(feature = "actix-handlers")]
+    #[actix_web::test]
+    async fn test_actix_gzip() {
+        use actix_web::{test, App};
+
+        let profile = large_profile();
+        let mock = Arc::new(MockBackend::new(MockState {
+            profile: profile.clone(),
+            ..MockState::default()
+        }));
+        let router = RouterBuilder::new().backend(mock).build();
+        let app = test::init_service(App::new().configure(|cfg| router.actix_routes(cfg))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/pprof/heap")
+            .insert_header(("Accept-Encoding", "gzip"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let encoding = resp.headers().get("content-encoding").and_then(|v| v.to_str().ok());
+        assert_eq!(Some("gzip"), encoding);
+        assert_eq!(profile, gunzip(&test::read_body(resp).await));
+
+        let req = test::TestRequest::get().uri("/pprof/heap").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(profile, test::read_body(resp).await.to_vec());
+    }
+
+    #[cfg(feature = "actix-handlers")]
+    #[actix_web::test]
+    async fn test_actix_routes() {
+        use actix_web::{test, App};
+
+        let mock = Arc::new(MockBackend::default());
+        let router = RouterBuilder::new().prefix("/debug/pprof").backend(mock.clone()).build();
+        let app = test::init_service(App::new().configure(|cfg| router.actix_routes(cfg))).await;
+
+        let req = test::TestRequest::get().uri("/debug/pprof/heap").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(MockState::default().profile, test::read_body(resp).await.to_vec());
+        assert_eq!(1, mock.state().dumps);
+
+        for (query, status) in [
+            ("prof.active:true,prof.reset:11", StatusCode::OK),
+            ("prof.active=false&prof.reset=%31%32", StatusCode::OK),
+            ("prof.active:true&prof.active=true", StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let req = test::TestRequest::post().uri(&format!("/debug/pprof/conf?{query}"));
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(status, resp.status().as_u16(), "{query}");
+        }
+        let state = mock.state();
+        assert_eq!((false, 12, 2), (state.active, state.lg_sample, state.resets));
+
+        let req = test::TestRequest::post()
+            .uri("/debug/pprof/conf")
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .set_payload(vec![b'0'; MAX_BODY_SIZE + 1]);
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, resp.status().as_u16());
+        assert_eq!(2, mock.state().resets);
+
+        let req = test::TestRequest::get().uri("/debug/pprof/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert!(test::read_body(resp).await.starts_with(b"<!DOCTYPE html>"));
+
+        let req = test::TestRequest::get().uri("/pprof/heap").to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status().as_u16());
+        let req = test::TestRequest::get().uri("/debug/pprof/nope").to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status().as_u16());
+
+        let req = test::TestRequest::put()
+            .uri("/debug/pprof/conf")
+            .insert_header(("Accept", "application/json"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, resp.status().as_u16());
+        assert_eq!(Some("GET, POST"), resp.headers().get("allow").and_then(|v| v.to_str().ok()));
+        assert_eq!(
+            br#"{"status":405,"error":"method not allowed"}"#,
+            test::read_body(resp).await.as_ref()
+        );
+    }
 }
@@ -15,10 +15,17 @@
 #![allow(unsafe_code, clippy::expect_used)]
 
 use lazy_static::lazy_static;
-use std::{ffi, fmt, fs, io, mem, ptr};
+use std::{
+    ffi, fmt, fs, io, mem, ptr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 use tikv_jemalloc_ctl::{raw, stats_print, Error as MallctlError};
 use tikv_jemalloc_sys::mallctlbymib;
 
+/// Arena index addressing all arenas, see `MALLCTL_ARENAS_ALL` in jemalloc.h.
+const MALLCTL_ARENAS_ALL: usize = 4096;
+
 lazy_static! {
     static ref MIB_OPT_PROF: [usize; 2] = {
         let mut mib = [0; 2];
@@ -55,6 +62,83 @@ lazy_static! {
         raw::name_to_mib(b"thread.prof.active\0", &mut mib).expect("mib");
         mib
     };
+    static ref MIB_ARENA_PURGE: [usize; 3] = {
+        let mut mib = [0; 3];
+        raw::name_to_mib(b"arena.0.purge\0", &mut mib).expect("mib");
+        mib[1] = MALLCTL_ARENAS_ALL;
+        mib
+    };
+    static ref MIB_EPOCH: [usize; 1] = {
+        let mut mib = [0; 1];
+        raw::name_to_mib(b"epoch\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_ALLOCATED: [usize; 2] = {
+        let mut mib = [0; 2];
+        raw::name_to_mib(b"stats.allocated\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_ACTIVE: [usize; 2] = {
+        let mut mib = [0; 2];
+        raw::name_to_mib(b"stats.active\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_RESIDENT: [usize; 2] = {
+        let mut mib = [0; 2];
+        raw::name_to_mib(b"stats.resident\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_MAPPED: [usize; 2] = {
+        let mut mib = [0; 2];
+        raw::name_to_mib(b"stats.mapped\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_RETAINED: [usize; 2] = {
+        let mut mib = [0; 2];
+        raw::name_to_mib(b"stats.retained\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_METADATA: [usize; 2] = {
+        let mut mib = [0; 2];
+        raw::name_to_mib(b"stats.metadata\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_ARENAS_NARENAS: [usize; 2] = {
+        let mut mib = [0; 2];
+        raw::name_to_mib(b"arenas.narenas\0", &mut mib).expect("mib");
+        mib
+    };
+    // Arena index at position 2 is a placeholder to be replaced before use.
+    static ref MIB_STATS_ARENAS_SMALL_NMALLOC: [usize; 5] = {
+        let mut mib = [0; 5];
+        raw::name_to_mib(b"stats.arenas.0.small.nmalloc\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_ARENAS_SMALL_NDALLOC: [usize; 5] = {
+        let mut mib = [0; 5];
+        raw::name_to_mib(b"stats.arenas.0.small.ndalloc\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_ARENAS_SMALL_NREQUESTS: [usize; 5] = {
+        let mut mib = [0; 5];
+        raw::name_to_mib(b"stats.arenas.0.small.nrequests\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_ARENAS_LARGE_NMALLOC: [usize; 5] = {
+        let mut mib = [0; 5];
+        raw::name_to_mib(b"stats.arenas.0.large.nmalloc\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_ARENAS_LARGE_NDALLOC: [usize; 5] = {
+        let mut mib = [0; 5];
+        raw::name_to_mib(b"stats.arenas.0.large.ndalloc\0", &mut mib).expect("mib");
+        mib
+    };
+    static ref MIB_STATS_ARENAS_LARGE_NREQUESTS: [usize; 5] = {
+        let mut mib = [0; 5];
+        raw::name_to_mib(b"stats.arenas.0.large.nrequests\0", &mut mib).expect("mib");
+        mib
+    };
 }
 
 /// Reads `opt.prof`.
@@ -140,7 +224,7 @@ pub fn reset(sample: Option<usize>) -> Result<(), Error> {
             None => ptr::null_mut(),
         };
         // SAFETY: use correct param type (*size_t) for this mallctl command.
-        unsafe { write_mib_ptr(&*MIB_PROF_RESET, value).map_err(Into::into) }
+        unsafe { write_mib_ptr(&*MIB_PROF_RESET, value) }
     })
 }
 
@@ -153,22 +237,33 @@ pub fn sample_interval() -> Result<usize, Error> {
     })
 }
 
+static DUMP_COUNT: AtomicU64 = AtomicU64::new(0);
+static DUMP_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Number and accumulated duration of successful profile dumps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DumpStats {
+    pub count: u64,
+    pub duration: Duration,
+}
+
+/// Returns the number and total duration of profile dumps done via [`dump`]
+/// and [`dump_to`].
+#[inline]
+pub fn dump_stats() -> DumpStats {
+    DumpStats {
+        count: DUMP_COUNT.load(Ordering::Relaxed),
+        duration: Duration::from_nanos(DUMP_NANOS.load(Ordering::Relaxed)),
+    }
+}
+
 /// Writes `prof.dump` causing a profile dump into a file.
 /// If a path is given, use the file as dump target and return its content.
 /// If not, jemalloc dumps the profile to a file based on name pattern.
 #[inline]
 pub fn dump(path: Option<&str>) -> Result<Option<Vec<u8>>, Error> {
     if_enabled(move || {
-        let ptr = match path {
-            Some(s) => ffi::CString::new(s)?.into_bytes_with_nul().as_ptr(),
-            None => ptr::null(),
-        };
-
-        // SAFETY: use correct param type (*char+\0) for this mallctl command.
-        unsafe {
-            raw::write_mib(&*MIB_PROF_DUMP, ptr)?;
-        }
-
+        write_dump(path)?;
         match path {
             Some(path) => {
                 let mut f = fs::File::open(path)?;
@@ -181,14 +276,160 @@ pub fn dump(path: Option<&str>) -> Result<Option<Vec<u8>>, Error> {
     })
 }
 
+/// Writes `prof.dump` into `path` without reading it back, e.g. to stream
+/// the file.
+#[inline]
+pub fn dump_to(path: &str) -> Result<(), Error> {
+    if_enabled(move || write_dump(Some(path)))
+}
+
+fn write_dump(path: Option<&str>) -> Result<(), Error> {
+    let path_c = path.map(ffi::CString::new).transpose()?;
+    let ptr = path_c.as_ref().map_or(ptr::null(), |s| s.as_ptr());
+
+    let start = Instant::now();
+    // SAFETY: use correct param type (*char+\0) for this mallctl command.
+    unsafe {
+        raw::write_mib(&*MIB_PROF_DUMP, ptr)?;
+    }
+    let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    DUMP_COUNT.fetch_add(1, Ordering::Relaxed);
+    DUMP_NANOS.fetch_add(nanos, Ordering::Relaxed);
+    Ok(())
+}
+
 pub fn stats() -> Result<Vec<u8>, Error> {
     let mut output = Vec::with_capacity(4096);
+    stats_to(&mut output)?;
+    Ok(output)
+}
+
+/// Writes the output of [`stats`] into `writer` as it is printed.
+#[inline]
+pub fn stats_to<W: io::Write>(writer: W) -> Result<(), Error> {
     let mut options = stats_print::Options::default();
     options.skip_per_arena = true;
+    stats_print::stats_print(writer, options)?;
+    Ok(())
+}
+
+/// Returns `stats_print` output in JSON format, without per-arena, size
+/// class and mutex details. See [`crate::profiling::stats`].
+pub fn stats_json() -> Result<Vec<u8>, Error> {
+    let mut output = Vec::with_capacity(4096);
+    let mut options = stats_print::Options::default();
+    options.json_format = true;
+    options.skip_per_arena = true;
+    options.skip_bin_size_classes = true;
+    options.skip_large_size_classes = true;
+    options.skip_mutex_statistics = true;
     stats_print::stats_print(&mut output, options)?;
     Ok(output)
 }
 
+/// Writes `arena.<MALLCTL_ARENAS_ALL>.purge`, returning unused dirty pages
+/// of all arenas to the OS.
+#[inline]
+pub fn purge() -> Result<(), Error> {
+    // SAFETY: this mallctl command takes no value.
+    unsafe { write_mib_ptr::<()>(&*MIB_ARENA_PURGE, ptr::null_mut()) }
+}
+
+/// Writes `epoch`, refreshing the cached `stats.*` values.
+#[inline]
+pub fn advance_epoch() -> Result<u64, Error> {
+    // SAFETY: use correct param type (uint64_t) for this mallctl command.
+    unsafe { raw::update_mib(&*MIB_EPOCH, 1_u64).map_err(Into::into) }
+}
+
+/// Global allocator statistics as cached at the last `epoch` advance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GlobalStats {
+    /// `stats.allocated`
+    pub allocated: usize,
+    /// `stats.active`
+    pub active: usize,
+    /// `stats.resident`
+    pub resident: usize,
+    /// `stats.mapped`
+    pub mapped: usize,
+    /// `stats.retained`
+    pub retained: usize,
+    /// `stats.metadata`
+    pub metadata: usize,
+}
+
+/// Reads `stats.{allocated,active,resident,mapped,retained,metadata}`.
+/// Call [`advance_epoch`] first to get fresh values.
+#[inline]
+pub fn global_stats() -> Result<GlobalStats, Error> {
+    // SAFETY: use correct return type (size_t) for these mallctl commands.
+    unsafe {
+        Ok(GlobalStats {
+            allocated: raw::read_mib(&*MIB_STATS_ALLOCATED)?,
+            active: raw::read_mib(&*MIB_STATS_ACTIVE)?,
+            resident: raw::read_mib(&*MIB_STATS_RESIDENT)?,
+            mapped: raw::read_mib(&*MIB_STATS_MAPPED)?,
+            retained: raw::read_mib(&*MIB_STATS_RETAINED)?,
+            metadata: raw::read_mib(&*MIB_STATS_METADATA)?,
+        })
+    }
+}
+
+/// Allocation counters of one size class of an arena.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocCounters {
+    /// `stats.arenas.<i>.<class>.nmalloc`
+    pub nmalloc: u64,
+    /// `stats.arenas.<i>.<class>.ndalloc`
+    pub ndalloc: u64,
+    /// `stats.arenas.<i>.<class>.nrequests`
+    pub nrequests: u64,
+}
+
+/// Per-arena statistics as cached at the last `epoch` advance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArenaStats {
+    pub index: u32,
+    pub small: AllocCounters,
+    pub large: AllocCounters,
+}
+
+/// Reads `stats.arenas.<i>.{small,large}.*` for all initialized arenas.
+/// Call [`advance_epoch`] first to get fresh values.
+pub fn arena_stats() -> Result<Vec<ArenaStats>, Error> {
+    fn read_counter(mib: &[usize; 5], index: u32) -> Result<u64, Error> {
+        let mut mib = *mib;
+        mib[2] = index as usize;
+        // SAFETY: use correct return type (uint64_t) for this mallctl command.
+        unsafe { raw::read_mib(&mib).map_err(Into::into) }
+    }
+
+    // SAFETY: use correct return type (unsigned) for this mallctl command.
+    let narenas: u32 = unsafe { raw::read_mib(&*MIB_ARENAS_NARENAS)? };
+    let mut arenas = Vec::with_capacity(narenas as usize);
+    for index in 0..narenas {
+        // Uninitialized arenas have no stats and fail the lookup.
+        let Ok(nmalloc) = read_counter(&MIB_STATS_ARENAS_SMALL_NMALLOC, index) else {
+            continue;
+        };
+        arenas.push(ArenaStats {
+            index,
+            small: AllocCounters {
+                nmalloc,
+                ndalloc: read_counter(&MIB_STATS_ARENAS_SMALL_NDALLOC, index)?,
+                nrequests: read_counter(&MIB_STATS_ARENAS_SMALL_NREQUESTS, index)?,
+            },
+            large: AllocCounters {
+                nmalloc: read_counter(&MIB_STATS_ARENAS_LARGE_NMALLOC, index)?,
+                ndalloc: read_counter(&MIB_STATS_ARENAS_LARGE_NDALLOC, index)?,
+                nrequests: read_counter(&MIB_STATS_ARENAS_LARGE_NREQUESTS, index)?,
+            },
+        });
+    }
+    Ok(arenas)
+}
+
 // Direct call to mallctl to allow passing null ptr if parameter is optional.
 unsafe fn write_mib_ptr<T>(mib: &[usize], value: *mut T) -> Result<(), Error> {
     match mallctlbymib(
@@ -226,10 +467,10 @@ pub enum Error {
 mod tests {
     use super::*;
 
+    // Profiling is configured by malloc_conf! in lib.rs.
+
     #[test]
-    #[ignore]
     fn test_prof_active() {
-        // _RJEM_MALLOC_CONF=prof:true,prof_active:false
         assert!(enabled().expect("get_prof_enabled"));
 
         assert!(!active().expect("get_prof_active"));
@@ -240,9 +481,7 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn test_prof_reset() {
-        // _RJEM_MALLOC_CONF=prof:true,prof_active:false,lg_prof_sample:10
         assert!(enabled().expect("get_prof_enabled"));
         assert_eq!(10, sample_interval().expect("get_prof_lg_sample"));
 
@@ -253,4 +492,28 @@ mod tests {
         reset(None).expect("prof_reset");
         assert_eq!(8, sample_interval().expect("get_prof_lg_sample"));
     }
+
+    #[test]
+    fn test_global_stats() {
+        advance_epoch().expect("advance_epoch");
+        let before = global_stats().expect("global_stats");
+        let buf = vec![1_u8; 4 << 20];
+        advance_epoch().expect("advance_epoch");
+        let after = global_stats().expect("global_stats");
+        assert!(after.allocated > before.allocated);
+        assert!(after.resident >= after.active);
+        drop(buf);
+    }
+
+    #[test]
+    fn test_purge() {
+        purge().expect("purge");
+    }
+
+    #[test]
+    fn test_arena_stats() {
+        advance_epoch().expect("advance_epoch");
+        let arenas = arena_stats().expect("arena_stats");
+        assert!(arenas.iter().any(|a| a.small.nmalloc > 0));
+    }
 }
@@ -0,0 +1,161 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Renders allocator metrics in the Prometheus text exposition format.
+//!
+//! Based on <https://prometheus.io/docs/instrumenting/exposition_formats/>.
+
+use crate::{
+    oompanic::{self, AllocKind},
+    profiling::mallctl,
+};
+use std::fmt::{self, Write as _};
+
+/// Advances the jemalloc epoch and renders all metrics.
+pub fn render() -> Result<String, mallctl::Error> {
+    mallctl::advance_epoch()?;
+    let stats = mallctl::global_stats()?;
+    let arenas = mallctl::arena_stats()?;
+
+    let mut out = String::with_capacity(4096);
+    write_metrics(&mut out, &stats, &arenas).expect("write to String");
+    Ok(out)
+}
+
+fn write_metrics(
+    out: &mut String,
+    stats: &mallctl::GlobalStats,
+    arenas: &[mallctl::ArenaStats],
+) -> fmt::Result {
+    for (name, help, value) in [
+        ("allocated", "Total number of bytes allocated by the application.", stats.allocated),
+        ("active", "Total number of bytes in active pages.", stats.active),
+        ("resident", "Total number of bytes in physically resident data pages.", stats.resident),
+        ("mapped", "Total number of bytes in active extents mapped.", stats.mapped),
+        ("retained", "Total number of bytes in retained virtual memory.", stats.retained),
+        ("metadata", "Total number of bytes dedicated to metadata.", stats.metadata),
+    ] {
+        header(out, &format!("jemalloc_{name}_bytes"), help, "gauge")?;
+        writeln!(out, "jemalloc_{name}_bytes {value}")?;
+    }
+
+    arena_counter(out, arenas, "allocations", "Number of allocations served.", |c| c.nmalloc)?;
+    arena_counter(out, arenas, "deallocations", "Number of deallocations served.", |c| c.ndalloc)?;
+    arena_counter(out, arenas, "requests", "Number of allocation requests served.", |c| {
+        c.nrequests
+    })?;
+
+    let enabled = mallctl::enabled().unwrap_or(false);
+    header(out, "jemalloc_prof_enabled", "Whether heap profiling is enabled (opt.prof).", "gauge")?;
+    writeln!(out, "jemalloc_prof_enabled {}", u8::from(enabled))?;
+    if enabled {
+        if let Ok(active) = mallctl::active() {
+            header(out, "jemalloc_prof_active", "Whether heap sampling is active.", "gauge")?;
+            writeln!(out, "jemalloc_prof_active {}", u8::from(active))?;
+        }
+        if let Ok(sample) = mallctl::sample_interval() {
+            header(out, "jemalloc_prof_lg_sample", "Heap sampling interval (log2).", "gauge")?;
+            writeln!(out, "jemalloc_prof_lg_sample {sample}")?;
+        }
+    }
+
+    header(
+        out,
+        "oompanic_allocation_failures_total",
+        "Number of failed allocations that caused a panic.",
+        "counter",
+    )?;
+    let failures = oompanic::failures();
+    for kind in [AllocKind::Alloc, AllocKind::AllocZeroed, AllocKind::Realloc] {
+        let value = failures.get(kind);
+        writeln!(out, "oompanic_allocation_failures_total{{kind=\"{kind}\"}} {value}")?;
+    }
+
+    header(
+        out,
+        "oompanic_reserve_bytes",
+        "Size of the emergency reserve currently held.",
+        "gauge",
+    )?;
+    writeln!(out, "oompanic_reserve_bytes {}", oompanic::reserve_size())?;
+    header(
+        out,
+        "oompanic_reserve_consumed_total",
+        "Number of times the emergency reserve was released on allocation failure.",
+        "counter",
+    )?;
+    writeln!(out, "oompanic_reserve_consumed_total {}", oompanic::reserve_consumed())?;
+
+    Ok(())
+}
+
+fn arena_counter<F>(
+    out: &mut String,
+    arenas: &[mallctl::ArenaStats],
+    name: &str,
+    help: &str,
+    value: F,
+) -> fmt::Result
+where
+    F: Fn(&mallctl::AllocCounters) -> u64,
+{
+    let metric = format!("jemalloc_arena_{name}_total");
+    header(out, &metric, help, "counter")?;
+    for arena in arenas {
+        for (class, counters) in [("small", &arena.small), ("large", &arena.large)] {
+            let index = arena.index;
+            let value = value(counters);
+            writeln!(out, "{metric}{{arena=\"{index}\",size_class=\"{class}\"}} {value}")?;
+        }
+    }
+    Ok(())
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} {kind}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_metrics() {
+        let stats = mallctl::GlobalStats { allocated: 42, ..Default::default() };
+        let arenas = [mallctl::ArenaStats {
+            index: 3,
+            small: mallctl::AllocCounters { nmalloc: 7, ndalloc: 5, nrequests: 9 },
+            large: mallctl::AllocCounters::default(),
+        }];
+        let mut out = String::new();
+        write_metrics(&mut out, &stats, &arenas).expect("write_metrics");
+
+        assert!(
+            out.contains("# TYPE jemalloc_allocated_bytes gauge\njemalloc_allocated_bytes 42\n")
+        );
+        assert!(
+            out.contains("jemalloc_arena_allocations_total{arena=\"3\",size_class=\"small\"} 7\n")
+        );
+        assert!(out.contains("jemalloc_arena_requests_total{arena=\"3\",size_class=\"large\"} 0\n"));
+        assert!(out.contains("# TYPE oompanic_allocation_failures_total counter\n"));
+    }
+
+    #[test]
+    fn test_render() {
+        let out = render().expect("render");
+        assert!(out.contains("\njemalloc_resident_bytes "));
+        assert!(out.contains("\njemalloc_prof_enabled "));
+    }
+}
@@ -12,7 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "jemalloc-profiling")]
+pub mod audit;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod auth;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod backend;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod body;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod heap;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod index;
 #[cfg(feature = "jemalloc-profiling")]
 pub mod jeprof;
 #[cfg(feature = "jemalloc-profiling")]
 pub mod mallctl;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod metrics;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod query;
+#[cfg(feature = "tower-handlers")]
+pub mod service;
+#[cfg(feature = "jemalloc-profiling")]
+pub mod stats;
@@ -0,0 +1,102 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Query string parsing for the HTTP handlers.
+//!
+//! Accepts jemalloc's `k:v,k:v` style as well as standard `k=v&k=v`, also
+//! mixed. A pair is split at the first `=` or `:`. Keys and values are
+//! percent-decoded after splitting, so `%2C`, `%26`, `%3A` and `%3D` can be
+//! used for literal delimiters; `+` decodes to a space.
+
+use std::collections::HashMap;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("duplicate parameter: {0}")]
+    DuplicateKey(String),
+
+    #[error("invalid percent-encoding: {0:?}")]
+    InvalidEncoding(String),
+}
+
+/// Parses a query string into a map. Empty pairs are skipped, keys without
+/// value map to an empty string.
+pub fn parse(query: Option<&str>) -> Result<HashMap<String, String>, Error> {
+    let mut params = HashMap::new();
+    for pair in query.unwrap_or_default().split(['&', ',']).filter(|p| !p.is_empty()) {
+        let (key, value) =
+            pair.find(['=', ':']).map_or((pair, ""), |i| (&pair[..i], &pair[i + 1..]));
+        let key = decode(key)?;
+        if params.contains_key(&key) {
+            return Err(Error::DuplicateKey(key));
+        }
+        params.insert(key, decode(value)?);
+    }
+    Ok(params)
+}
+
+fn decode(s: &str) -> Result<String, Error> {
+    let invalid = || Error::InvalidEncoding(s.to_owned());
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hi =
+                    bytes.next().and_then(|c| char::from(c).to_digit(16)).ok_or_else(invalid)?;
+                let lo =
+                    bytes.next().and_then(|c| char::from(c).to_digit(16)).ok_or_else(invalid)?;
+                #[allow(clippy::cast_possible_truncation)] // Two hex digits.
+                out.push((hi << 4 | lo) as u8);
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(query: &str) -> Vec<(String, String)> {
+        let mut pairs: Vec<_> = parse(Some(query)).expect("parse").into_iter().collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn test_parse() {
+        let expected = vec![
+            ("prof.active".to_owned(), "true".to_owned()),
+            ("seconds".to_owned(), "30".to_owned()),
+        ];
+        assert_eq!(expected, pairs("prof.active:true,seconds:30"));
+        assert_eq!(expected, pairs("prof.active=true&seconds=30"));
+        assert_eq!(expected, pairs("prof.active=true,seconds:30&"));
+        assert_eq!(vec![("a b".to_owned(), "x,y=z:".to_owned())], pairs("a+b=x%2Cy%3dz:"));
+        assert_eq!(vec![("flag".to_owned(), String::new())], pairs("flag"));
+        assert!(parse(None).expect("parse").is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Err(Error::DuplicateKey("a".to_owned())), parse(Some("a:1&a=2")));
+        assert_eq!(Err(Error::DuplicateKey("a".to_owned())), parse(Some("a&%61")));
+        assert_eq!(Err(Error::InvalidEncoding("%zz".to_owned())), parse(Some("k=%zz")));
+        assert_eq!(Err(Error::InvalidEncoding("%4".to_owned())), parse(Some("k=%4")));
+        assert_eq!(Err(Error::InvalidEncoding("%ff".to_owned())), parse(Some("%ff")));
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `tower` integration for hyper 1.x and axum (feature `tower-handlers`).
+//!
+//! [`JeprofService`] accepts requests with any [`http_body::Body`]. Bodies
+//! are only read once the request is authorized, and up to [`MAX_BODY_SIZE`]
+//! bytes. Handlers
+//! block on mallctl calls and dump files, so they and the reads of streamed
+//! response bodies run on tokio's blocking pool.
+//!
+//! ```ignore
+//! let app = axum::Router::new().merge(jeprof::RouterBuilder::new().build().axum_router());
+//! ```
+
+use crate::profiling::{
+    auth::ClientAddr,
+    body::{Body, Chunks},
+    jeprof::{ErrorResponse, Router, MAX_BODY_SIZE},
+};
+use bytes::Bytes;
+use http::{Request, Response};
+use http_body::Frame;
+use http_body_util::{BodyExt as _, LengthLimitError, Limited};
+use std::{
+    convert::Infallible,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::task::JoinHandle;
+
+/// A [`tower_service::Service`] serving a [`Router`]. Cheap to clone.
+#[derive(Clone)]
+pub struct JeprofService {
+    router: Router,
+}
+
+impl JeprofService {
+    #[must_use]
+    pub const fn new(router: Router) -> Self {
+        Self { router }
+    }
+}
+
+impl Router {
+    /// Returns a [`JeprofService`] serving this router.
+    #[must_use]
+    pub fn tower_service(&self) -> JeprofService {
+        JeprofService::new(self.clone())
+    }
+
+    /// Returns an axum router serving all paths below the prefix, to be
+    /// merged into an app. Client addresses are taken from
+    /// [`axum::extract::ConnectInfo`] if available.
+    pub fn axum_router(&self) -> axum::Router {
+        let service = self.tower_service();
+        axum::Router::new()
+            .route_service(&format!("{}/", self.prefix()), service.clone())
+            .route_service(&format!("{}/{{*path}}", self.prefix()), service)
+    }
+}
+
+impl<B> tower_service::Service<Request<B>> for JeprofService
+where
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let router = self.router.clone();
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            if parts.extensions.get::<ClientAddr>().is_none() {
+                let connect_info =
+                    parts.extensions.get::<axum::extract::ConnectInfo<SocketAddr>>().map(|c| c.0);
+                if let Some(addr) = connect_info {
+                    parts.extensions.insert(ClientAddr(addr));
+                }
+            }
+            let head = Request::from_parts(parts, ());
+            if router.authorize(&head).is_err() {
+                // Without the body, the router answers and audits the rejection.
+                let req = head.map(|()| Vec::new());
+                return Ok(match router.handle_streaming(req) {
+                    Ok(resp) => resp.map(ResponseBody::new),
+                    Err(e) => error(&ErrorResponse::Internal(e.to_string())),
+                });
+            }
+            let body = match Limited::new(body, MAX_BODY_SIZE).collect().await {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(e) if e.is::<LengthLimitError>() => {
+                    return Ok(error(&ErrorResponse::PayloadTooLarge));
+                }
+                Err(e) => return Ok(error(&ErrorResponse::BadRequest(e.to_string()))),
+            };
+            let req = head.map(|()| body);
+            let resp = tokio::task::spawn_blocking(move || router.handle_streaming(req)).await;
+            Ok(match resp {
+                Ok(Ok(resp)) => resp.map(ResponseBody::new),
+                Ok(Err(e)) => error(&ErrorResponse::Internal(e.to_string())),
+                Err(e) => error(&ErrorResponse::Internal(format!("handler failed: {e}"))),
+            })
+        })
+    }
+}
+
+fn error(err: &ErrorResponse) -> Response<ResponseBody> {
+    err.to_response(false)
+        .unwrap_or_else(|_| Response::new(Vec::new()))
+        .map(|body| ResponseBody::new(Body::Full(body)))
+}
+
+/// The [`http_body::Body`] of [`JeprofService`] responses. Streamed bodies
+/// are read chunk by chunk on the blocking pool.
+pub struct ResponseBody {
+    state: State,
+}
+
+enum State {
+    Full(Option<Bytes>),
+    Idle(Chunks),
+    Reading(JoinHandle<(Chunks, Option<io::Result<Vec<u8>>>)>),
+    Done,
+}
+
+impl ResponseBody {
+    #[must_use]
+    pub fn new(body: Body) -> Self {
+        let state = match body {
+            Body::Full(bytes) if bytes.is_empty() => State::Done,
+            Body::Full(bytes) => State::Full(Some(bytes.into())),
+            body @ Body::Stream(_) => State::Idle(body.chunks()),
+        };
+        Self { state }
+    }
+}
+
+impl http_body::Body for ResponseBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        loop {
+            match std::mem::replace(&mut self.state, State::Done) {
+                State::Full(bytes) => return Poll::Ready(bytes.map(|b| Ok(Frame::data(b)))),
+                State::Idle(mut chunks) => {
+                    self.state = State::Reading(tokio::task::spawn_blocking(move || {
+                        let chunk = chunks.next();
+                        (chunks, chunk)
+                    }));
+                }
+                State::Reading(mut handle) => match Pin::new(&mut handle).poll(cx) {
+                    Poll::Pending => {
+                        self.state = State::Reading(handle);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Ok((chunks, Some(Ok(chunk))))) => {
+                        self.state = State::Idle(chunks);
+                        return Poll::Ready(Some(Ok(Frame::data(chunk.into()))));
+                    }
+                    Poll::Ready(Ok((_, Some(Err(e))))) => return Poll::Ready(Some(Err(e))),
+                    Poll::Ready(Ok((_, None))) => return Poll::Ready(None),
+                    Poll::Ready(Err(e)) => {
+                        return Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, e))));
+                    }
+                },
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        match &self.state {
+            State::Full(Some(bytes)) => http_body::SizeHint::with_exact(bytes.len() as u64),
+            State::Done => http_body::SizeHint::with_exact(0),
+            _ => http_body::SizeHint::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiling::{
+        auth::Policy,
+        backend::{MockBackend, MockState},
+        jeprof::RouterBuilder,
+    };
+    use http::{header, Method, StatusCode};
+    use http_body_util::Full;
+    use std::sync::Arc;
+    use tower::ServiceExt as _;
+
+    async fn body(resp: Response<ResponseBody>) -> Vec<u8> {
+        resp.into_body().collect().await.expect("body").to_bytes().to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let mock = Arc::new(MockBackend::default());
+        let service = RouterBuilder::new().backend(mock.clone()).build().tower_service();
+
+        let req = Request::get("/pprof/heap").body(Full::new(Bytes::new())).expect("request");
+        let resp = service.clone().oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(MockState::default().profile, body(resp).await);
+        assert_eq!(1, mock.state().dumps);
+
+        let req = Request::post("/pprof/conf")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Full::new(Bytes::from_static(b"prof.active=true")))
+            .expect("request");
+        let resp = service.clone().oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(mock.state().active);
+
+        let req = Request::get("/pprof/nope").body(Full::new(Bytes::new())).expect("request");
+        let resp = service.oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert_eq!(b"not found\r\n", body(resp).await.as_slice());
+    }
+
+    /// Fails when read.
+    struct Unread;
+
+    impl http_body::Body for Unread {
+        type Data = Bytes;
+        type Error = io::Error;
+
+        fn poll_frame(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+            Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, "body read"))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_limits() {
+        let router = RouterBuilder::new()
+            .backend(Arc::new(MockBackend::default()))
+            .auth(Policy::new().bearer_token("s3cret"))
+            .build();
+        let service = router.tower_service();
+
+        let req = Request::post("/pprof/conf").body(Unread).expect("request");
+        let resp = service.clone().oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        let req = Request::post("/pprof/nope").body(Unread).expect("request");
+        let resp = service.clone().oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+
+        let req = Request::post("/pprof/symbol")
+            .header(header::AUTHORIZATION, "Bearer s3cret")
+            .body(Full::new(Bytes::from(vec![b'0'; MAX_BODY_SIZE + 1])))
+            .expect("request");
+        let resp = service.clone().oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, resp.status());
+        let req = Request::post("/pprof/symbol")
+            .header(header::AUTHORIZATION, "Bearer s3cret")
+            .body(Unread)
+            .expect("request");
+        let resp = service.oneshot(req).await.expect("infallible");
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+
+    #[tokio::test]
+    async fn test_axum_router() {
+        let mock = Arc::new(MockBackend::default());
+        let router = RouterBuilder::new()
+            .prefix("/debug/pprof")
+            .backend(mock)
+            .auth(Policy::new().loopback_only())
+            .build();
+        let app = axum::Router::new()
+            .route_service("/health", tower::service_fn(|_| async { Ok::<_, Infallible>("ok") }))
+            .merge(router.axum_router());
+        let request = |method: Method, uri: &str, client: &str| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .expect("request");
+            let addr: SocketAddr = client.parse().expect("addr");
+            req.extensions_mut().insert(axum::extract::ConnectInfo(addr));
+            app.clone().oneshot(req)
+        };
+
+        let resp = request(Method::GET, "/debug/pprof/conf", "127.0.0.1:1234").await.expect("resp");
+        assert_eq!(StatusCode::OK, resp.status());
+        let resp = request(Method::GET, "/debug/pprof/", "[::1]:1234").await.expect("resp");
+        assert_eq!(StatusCode::OK, resp.status());
+        let resp = request(Method::GET, "/debug/pprof/conf", "10.0.0.1:1234").await.expect("resp");
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        let resp = request(Method::PUT, "/debug/pprof/conf", "127.0.0.1:1").await.expect("resp");
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, resp.status());
+        let resp = request(Method::GET, "/debug/pprof/a/b", "127.0.0.1:1").await.expect("resp");
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let resp = request(Method::GET, "/health", "127.0.0.1:1").await.expect("resp");
+        assert_eq!(StatusCode::OK, resp.status());
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed subset of jemalloc's JSON statistics.
+//!
+//! Parses the output of `malloc_stats_print` with option `J`, see
+//! [`super::mallctl::stats_json`]. Fields not listed here are ignored, so
+//! the serialized form is stable across jemalloc versions. Byte counts are
+//! in bytes, page counts in pages.
+
+use serde::{Deserialize, Serialize};
+
+/// Statistics served by `/pprof/stats?format=json`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct JemallocStats {
+    pub version: String,
+    pub stats: Totals,
+    /// Merged statistics of all arenas.
+    #[serde(rename = "stats.arenas")]
+    pub arenas: Arenas,
+}
+
+/// Process-wide totals (`stats.*`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Totals {
+    pub allocated: u64,
+    pub active: u64,
+    pub metadata: u64,
+    pub resident: u64,
+    pub mapped: u64,
+    pub retained: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Arenas {
+    pub merged: ArenaStats,
+}
+
+/// Statistics of an arena (`stats.arenas.<i>.*`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ArenaStats {
+    pub nthreads: u64,
+    pub pactive: u64,
+    pub pdirty: u64,
+    pub pmuzzy: u64,
+    pub small: SizeClassStats,
+    pub large: SizeClassStats,
+}
+
+/// Allocation counters of small or large size classes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SizeClassStats {
+    pub allocated: u64,
+    pub nmalloc: u64,
+    pub ndalloc: u64,
+    pub nrequests: u64,
+}
+
+#[derive(Deserialize)]
+struct Document {
+    jemalloc: JemallocStats,
+}
+
+/// Parses `malloc_stats_print` JSON output.
+pub fn parse(json: &[u8]) -> Result<JemallocStats, serde_json::Error> {
+    serde_json::from_slice::<Document>(json).map(|doc| doc.jemalloc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiling::mallctl;
+
+    #[test]
+    fn test_parse() {
+        mallctl::advance_epoch().expect("advance_epoch");
+        let stats = parse(&mallctl::stats_json().expect("stats_json")).expect("parse");
+        assert!(!stats.version.is_empty());
+        assert!(stats.stats.allocated > 0);
+        assert!(stats.stats.resident >= stats.stats.active);
+        assert!(stats.arenas.merged.small.nmalloc > 0);
+
+        let json = serde_json::to_string(&stats.stats).expect("serialize");
+        assert!(json.starts_with(r#"{"allocated":"#), "{json}");
+        assert!(parse(b"{}").is_err());
+    }
+}
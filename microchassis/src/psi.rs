@@ -0,0 +1,551 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watches memory pressure stall information (PSI) and calls back when
+//! stall thresholds are exceeded.
+//!
+//! Based on <https://docs.kernel.org/accounting/psi.html>. Kernel triggers
+//! are used if supported, otherwise the averages are polled. The watcher can
+//! also fire when cgroup memory usage reaches a [`Watermark`].
+
+use crate::cgroup::{Cgroup, Watermark};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+    os::{fd::AsRawFd as _, unix::fs::OpenOptionsExt as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// System-wide memory pressure. Use [`crate::cgroup::Cgroup::memory_pressure_path`]
+/// for the pressure of the process's cgroup.
+pub const SYSTEM_MEMORY_PRESSURE: &str = "/proc/pressure/memory";
+
+/// One line of a pressure file. Averages are percentages.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PressureLine {
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    pub total: Duration,
+}
+
+/// Contents of a pressure file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pressure {
+    pub some: PressureLine,
+    pub full: PressureLine,
+}
+
+/// Reads and parses a pressure file.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Pressure, Error> {
+    let content = fs::read_to_string(path.as_ref())?;
+    parse(&content).ok_or(Error::Parse(content))
+}
+
+fn parse(content: &str) -> Option<Pressure> {
+    fn parse_line(fields: &str) -> Option<PressureLine> {
+        let mut line = PressureLine::default();
+        for field in fields.split_whitespace() {
+            let (key, value) = field.split_once('=')?;
+            match key {
+                "avg10" => line.avg10 = value.parse().ok()?,
+                "avg60" => line.avg60 = value.parse().ok()?,
+                "avg300" => line.avg300 = value.parse().ok()?,
+                "total" => line.total = Duration::from_micros(value.parse().ok()?),
+                _ => (),
+            }
+        }
+        Some(line)
+    }
+
+    let mut pressure = Pressure::default();
+    for line in content.lines() {
+        if let Some(fields) = line.strip_prefix("some ") {
+            pressure.some = parse_line(fields)?;
+        } else if let Some(fields) = line.strip_prefix("full ") {
+            pressure.full = parse_line(fields)?;
+        }
+    }
+    Some(pressure)
+}
+
+/// Which tasks a trigger measures stalls of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stall {
+    /// At least some tasks are stalled.
+    Some,
+    /// All non-idle tasks are stalled.
+    Full,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Some => "some",
+            Self::Full => "full",
+        })
+    }
+}
+
+/// Fires when tasks were stalled for `threshold` within `window`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trigger {
+    stall: Stall,
+    threshold: Duration,
+    window: Duration,
+}
+
+impl Trigger {
+    /// Fails with [`Error::ZeroWindow`] if `window` is zero.
+    pub const fn new(stall: Stall, threshold: Duration, window: Duration) -> Result<Self, Error> {
+        if window.is_zero() {
+            return Err(Error::ZeroWindow);
+        }
+        Ok(Self { stall, threshold, window })
+    }
+
+    #[must_use]
+    #[inline]
+    pub const fn stall(&self) -> Stall {
+        self.stall
+    }
+
+    #[must_use]
+    #[inline]
+    pub const fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    #[must_use]
+    #[inline]
+    pub const fn window(&self) -> Duration {
+        self.window
+    }
+
+    // Approximates the trigger with the 10s average when polling.
+    fn exceeded_by(&self, pressure: &Pressure) -> bool {
+        let line = match self.stall {
+            Stall::Some => &pressure.some,
+            Stall::Full => &pressure.full,
+        };
+        let percent = self.threshold.as_secs_f64() / self.window.as_secs_f64() * 100.0;
+        line.avg10 >= percent
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.stall, self.threshold.as_micros(), self.window.as_micros())
+    }
+}
+
+/// Passed to callbacks when a trigger fired.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PressureEvent {
+    pub trigger: Trigger,
+    /// Pressure read right after the trigger fired.
+    pub pressure: Pressure,
+}
+
+type Callback = Box<dyn Fn(&PressureEvent) + Send + Sync>;
+
+/// Passed to callbacks when memory usage reached a watermark.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatermarkEvent {
+    pub watermark: Watermark,
+    /// The watermark in bytes.
+    pub threshold: u64,
+    /// `memory.current` of the cgroup.
+    pub current: u64,
+}
+
+type WatermarkCallback = Box<dyn Fn(&WatermarkEvent) + Send + Sync>;
+
+/// Configures and spawns a thread watching a pressure file.
+pub struct Watcher {
+    path: PathBuf,
+    triggers: Vec<Trigger>,
+    callbacks: Vec<Callback>,
+    watermarks: Vec<(Cgroup, Watermark)>,
+    watermark_callbacks: Vec<WatermarkCallback>,
+    dump: bool,
+    purge: bool,
+    kernel_triggers: bool,
+    poll_interval: Duration,
+}
+
+impl Watcher {
+    /// Watches `path`, e.g. [`SYSTEM_MEMORY_PRESSURE`].
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            triggers: Vec::new(),
+            callbacks: Vec::new(),
+            watermarks: Vec::new(),
+            watermark_callbacks: Vec::new(),
+            dump: false,
+            purge: false,
+            kernel_triggers: true,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    #[must_use]
+    pub fn trigger(mut self, trigger: Trigger) -> Self {
+        self.triggers.push(trigger);
+        self
+    }
+
+    #[must_use]
+    pub fn on_pressure<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&PressureEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.push(Box::new(f));
+        self
+    }
+
+    /// Fires when `memory.current` of `cgroup` rises to `watermark`, e.g.
+    /// `Watermark::PercentOfLimit(90.0)`. Checked every poll interval, or
+    /// every 200ms with kernel triggers. Percentages follow limit changes.
+    #[must_use]
+    pub fn watermark(mut self, cgroup: Cgroup, watermark: Watermark) -> Self {
+        self.watermarks.push((cgroup, watermark));
+        self
+    }
+
+    #[must_use]
+    pub fn on_watermark<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&WatermarkEvent) + Send + Sync + 'static,
+    {
+        self.watermark_callbacks.push(Box::new(f));
+        self
+    }
+
+    /// Dumps a heap profile via `prof.dump` when a trigger fires or a
+    /// watermark is reached.
+    #[must_use]
+    pub fn dump_on_pressure(mut self, dump: bool) -> Self {
+        self.dump = dump;
+        self
+    }
+
+    /// Purges all jemalloc arenas when a trigger fires or a watermark is
+    /// reached.
+    #[must_use]
+    pub fn purge_on_pressure(mut self, purge: bool) -> Self {
+        self.purge = purge;
+        self
+    }
+
+    /// Disables kernel triggers and always polls the averages.
+    #[must_use]
+    pub fn kernel_triggers(mut self, enabled: bool) -> Self {
+        self.kernel_triggers = enabled;
+        self
+    }
+
+    /// Sets how often the averages are polled if kernel triggers are not used.
+    #[must_use]
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Starts watching in a background thread.
+    pub fn spawn(self) -> io::Result<WatcherHandle> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread =
+            thread::Builder::new().name("psi".to_owned()).spawn(move || self.run(&thread_stop))?;
+        Ok(WatcherHandle { stop, thread: Some(thread) })
+    }
+
+    fn run(&self, stop: &AtomicBool) {
+        if self.kernel_triggers {
+            match self.open_triggers() {
+                Ok(files) => return self.run_kernel_triggers(&files, stop),
+                Err(e) => tracing::debug!("psi: kernel triggers unavailable, polling: {e}"),
+            }
+        }
+        self.run_polling(stop);
+    }
+
+    fn open_triggers(&self) -> io::Result<Vec<fs::File>> {
+        self.triggers
+            .iter()
+            .map(|trigger| {
+                let mut f = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(&self.path)?;
+                f.write_all(format!("{trigger}\0").as_bytes())?;
+                Ok(f)
+            })
+            .collect()
+    }
+
+    #[allow(unsafe_code)]
+    fn run_kernel_triggers(&self, files: &[fs::File], stop: &AtomicBool) {
+        let mut fds: Vec<_> = files
+            .iter()
+            .map(|f| libc::pollfd { fd: f.as_raw_fd(), events: libc::POLLPRI, revents: 0 })
+            .collect();
+        let mut above = vec![false; self.watermarks.len()];
+        while !stop.load(Ordering::Relaxed) {
+            self.check_watermarks(&mut above);
+            // SAFETY: fds is a valid array of pollfd structs for its length.
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 200) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                tracing::warn!("psi: poll failed: {err}");
+                return;
+            }
+            for (pollfd, trigger) in fds.iter_mut().zip(&self.triggers) {
+                if pollfd.revents & libc::POLLERR != 0 {
+                    tracing::warn!("psi: {:?} is gone, stopping", self.path);
+                    return;
+                }
+                if pollfd.revents & libc::POLLPRI != 0 {
+                    self.fire(*trigger);
+                }
+                pollfd.revents = 0;
+            }
+        }
+    }
+
+    fn run_polling(&self, stop: &AtomicBool) {
+        let mut last_fired: Vec<Option<Instant>> = vec![None; self.triggers.len()];
+        let mut above = vec![false; self.watermarks.len()];
+        while !stop.load(Ordering::Relaxed) {
+            self.check_watermarks(&mut above);
+            if let Ok(pressure) = read(&self.path) {
+                for (trigger, last) in self.triggers.iter().zip(&mut last_fired) {
+                    let due = last.map_or(true, |t| t.elapsed() >= trigger.window);
+                    if due && trigger.exceeded_by(&pressure) {
+                        *last = Some(Instant::now());
+                        self.fire(*trigger);
+                    }
+                }
+            }
+            thread::park_timeout(self.poll_interval);
+        }
+    }
+
+    // Fires once per crossing; usage has to drop below before firing again.
+    fn check_watermarks(&self, above: &mut [bool]) {
+        for ((cgroup, watermark), above) in self.watermarks.iter().zip(above) {
+            let (Ok(Some(threshold)), Ok(current)) =
+                (cgroup.resolve(*watermark), cgroup.memory_current())
+            else {
+                continue;
+            };
+            let reached = current >= threshold;
+            if reached && !*above {
+                tracing::info!("psi: memory.current {current} reached watermark {watermark}");
+                let event = WatermarkEvent { watermark: *watermark, threshold, current };
+                for f in &self.watermark_callbacks {
+                    f(&event);
+                }
+                self.respond(&format!("watermark {watermark}"));
+            }
+            *above = reached;
+        }
+    }
+
+    fn fire(&self, trigger: Trigger) {
+        let pressure = read(&self.path).unwrap_or_default();
+        tracing::info!("psi: {trigger} exceeded, some avg10={}", pressure.some.avg10);
+        let event = PressureEvent { trigger, pressure };
+        for f in &self.callbacks {
+            f(&event);
+        }
+        self.respond(&trigger.to_string());
+    }
+
+    // Dumps and purges as configured, audited with `params`.
+    #[cfg_attr(not(feature = "jemalloc-profiling"), allow(unused_variables, clippy::unused_self))]
+    fn respond(&self, params: &str) {
+        #[cfg(feature = "jemalloc-profiling")]
+        {
+            use crate::profiling::{audit, mallctl};
+
+            if self.dump {
+                let result = mallctl::dump(None).map(|_| ());
+                if let Err(e) = &result {
+                    tracing::warn!("psi: failed to dump profile: {e}");
+                }
+                audit::global().record("psi", "dump", params, result.map_err(|e| e.to_string()));
+            }
+            if self.purge {
+                let result = mallctl::purge();
+                if let Err(e) = &result {
+                    tracing::warn!("psi: failed to purge arenas: {e}");
+                }
+                audit::global().record("psi", "purge", params, result.map_err(|e| e.to_string()));
+            }
+        }
+    }
+}
+
+/// Stops the watcher thread when dropped.
+pub struct WatcherHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl WatcherHandle {
+    /// Stops the watcher thread and waits for it to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for WatcherHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error {
+    #[error("psi: cannot parse {0:?}")]
+    Parse(String),
+
+    #[error("psi: trigger window must not be zero")]
+    ZeroWindow,
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    const CONTENT: &str = "some avg10=50.00 avg60=10.00 avg300=1.50 total=123456\n\
+                           full avg10=0.00 avg60=0.00 avg300=0.00 total=42\n";
+
+    #[test]
+    fn test_parse() {
+        let pressure = parse(CONTENT).expect("parse");
+        assert_eq!(
+            PressureLine {
+                avg10: 50.0,
+                avg60: 10.0,
+                avg300: 1.5,
+                total: Duration::from_micros(123_456)
+            },
+            pressure.some
+        );
+        assert_eq!(Duration::from_micros(42), pressure.full.total);
+        assert!(parse("some avg10=x\n").is_none());
+    }
+
+    #[test]
+    fn test_trigger() {
+        let trigger = Trigger::new(Stall::Some, Duration::from_millis(150), Duration::from_secs(1))
+            .expect("trigger");
+        assert_eq!("some 150000 1000000", trigger.to_string());
+        let pressure = parse(CONTENT).expect("parse");
+        assert!(trigger.exceeded_by(&pressure));
+        assert!(!Trigger { stall: Stall::Full, ..trigger }.exceeded_by(&pressure));
+        assert!(matches!(
+            Trigger::new(Stall::Some, Duration::from_millis(150), Duration::ZERO),
+            Err(Error::ZeroWindow)
+        ));
+    }
+
+    #[test]
+    fn test_watcher_polling() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("memory.pressure");
+        fs::write(&path, CONTENT).expect("write");
+
+        let (tx, rx) = mpsc::sync_channel(8);
+        let handle = Watcher::new(&path)
+            .trigger(
+                Trigger::new(Stall::Full, Duration::from_millis(100), Duration::from_secs(1))
+                    .expect("trigger"),
+            )
+            .trigger(
+                Trigger::new(Stall::Some, Duration::from_millis(100), Duration::from_secs(1))
+                    .expect("trigger"),
+            )
+            .on_pressure(move |event| {
+                let _ = tx.try_send(*event);
+            })
+            .kernel_triggers(false)
+            .poll_interval(Duration::from_millis(10))
+            .spawn()
+            .expect("spawn");
+
+        let event = rx.recv_timeout(Duration::from_secs(5)).expect("event");
+        assert_eq!(Stall::Some, event.trigger.stall());
+        assert!((event.pressure.some.avg10 - 50.0).abs() < f64::EPSILON);
+        handle.stop();
+    }
+
+    #[test]
+    fn test_watermark() {
+        let root = crate::cgroup::tests::fake_root("4194304\n", "max\n");
+        let cgroup = Cgroup::discover_in(root.path()).expect("discover");
+
+        let (tx, rx) = mpsc::sync_channel(8);
+        let handle = Watcher::new(root.path().join("missing"))
+            .watermark(cgroup.clone(), Watermark::PercentOfLimit(50.0))
+            .watermark(cgroup, Watermark::PercentOfLimit(20.0))
+            .on_watermark(move |event| {
+                let _ = tx.try_send(*event);
+            })
+            .kernel_triggers(false)
+            .poll_interval(Duration::from_millis(10))
+            .spawn()
+            .expect("spawn");
+
+        let event = rx.recv_timeout(Duration::from_secs(5)).expect("event");
+        assert_eq!(
+            WatermarkEvent {
+                watermark: Watermark::PercentOfLimit(20.0),
+                threshold: 838_860,
+                current: 1 << 20
+            },
+            event
+        );
+        // Only fires again after dropping below.
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        handle.stop();
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2023 Folke Behrens
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allocation assertions for tests.
+//!
+//! Based on jemalloc's `thread.allocated` and `thread.deallocated` counters,
+//! so only allocations of the calling thread are seen and jemalloc must be
+//! the global allocator. Lazily initialized statics allocated inside the
+//! closure count as leaks; warm them up before asserting.
+//!
+//! ```ignore
+//! microchassis::testing::assert_no_leaks(|| parse(input));
+//! microchassis::assert_max_alloc_bytes!(4096, { encode(&message) });
+//! ```
+//!
+//! If heap profiling is active, a failing assertion dumps a heap profile and
+//! names its path in the panic message.
+
+use std::fmt;
+use tikv_jemalloc_ctl::thread;
+
+/// Bytes allocated and deallocated by a closure.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocSummary {
+    pub allocated: u64,
+    pub deallocated: u64,
+}
+
+impl AllocSummary {
+    /// Returns the bytes allocated but not deallocated.
+    #[must_use]
+    #[inline]
+    pub fn leaked(&self) -> u64 {
+        self.allocated.saturating_sub(self.deallocated)
+    }
+}
+
+impl fmt::Display for AllocSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "allocated {} bytes, deallocated {} bytes, leaked {} bytes",
+            self.allocated,
+            self.deallocated,
+            self.leaked()
+        )
+    }
+}
+
+/// Runs `f` and returns its result with the bytes it (de)allocated on the
+/// current thread.
+pub fn measure<F: FnOnce() -> R, R>(f: F) -> (R, AllocSummary) {
+    let allocated = thread::allocatedp::read().expect("thread.allocatedp");
+    let deallocated = thread::deallocatedp::read().expect("thread.deallocatedp");
+    let (a0, d0) = (allocated.get(), deallocated.get());
+    let result = f();
+    let (a1, d1) = (allocated.get(), deallocated.get());
+    (result, AllocSummary { allocated: a1 - a0, deallocated: d1 - d0 })
+}
+
+/// Runs `f` and panics if it didn't free all memory it allocated.
+#[track_caller]
+pub fn assert_no_leaks<F: FnOnce() -> R, R>(f: F) -> R {
+    let (result, summary) = measure(f);
+    assert!(summary.leaked() == 0, "memory leaked: {summary}{}", heap_profile());
+    result
+}
+
+/// Runs `f` and panics if it allocated more than `max` bytes in total.
+/// See also [`crate::assert_max_alloc_bytes!`].
+#[track_caller]
+pub fn assert_max_alloc_bytes<F: FnOnce() -> R, R>(max: u64, f: F) -> R {
+    let (result, summary) = measure(f);
+    assert!(
+        summary.allocated <= max,
+        "allocated more than {max} bytes: {summary}{}",
+        heap_profile()
+    );
+    result
+}
+
+/// Evaluates a block and panics if it allocated more than the given number
+/// of bytes, e.g. `assert_max_alloc_bytes!(1024, { vec![0_u8; 512] })`.
+#[macro_export]
+macro_rules! assert_max_alloc_bytes {
+    ($max:expr, $body:block) => {
+        $crate::testing::assert_max_alloc_bytes($max, || $body)
+    };
+}
+
+// Dumps a heap profile for the failure message if profiling is active.
+#[cfg(feature = "jemalloc-profiling")]
+fn heap_profile() -> String {
+    use crate::profiling::mallctl;
+
+    if !mallctl::active().unwrap_or(false) {
+        return String::new();
+    }
+    let path = tempfile::Builder::new()
+        .prefix("microchassis.")
+        .suffix(".heap")
+        .tempfile()
+        .and_then(|f| f.keep().map_err(|e| e.error));
+    match path {
+        Ok((_, path)) => match path.to_str().map(|p| mallctl::dump(Some(p))) {
+            Some(Ok(_)) => format!("\nheap profile: {}", path.display()),
+            Some(Err(e)) => format!("\nheap profile failed: {e}"),
+            None => String::new(),
+        },
+        Err(e) => format!("\nheap profile failed: {e}"),
+    }
+}
+
+#[cfg(not(feature = "jemalloc-profiling"))]
+fn heap_profile() -> String {
+    String::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn test_measure() {
+        let (v, summary) = measure(|| vec![0_u8; 1000]);
+        assert!(summary.allocated >= 1000, "{summary}");
+        assert!(summary.leaked() >= 1000, "{summary}");
+        let ((), summary) = measure(|| drop(v));
+        assert_eq!(0, summary.allocated);
+        assert!(summary.deallocated >= 1000, "{summary}");
+    }
+
+    #[test]
+    fn test_assert_no_leaks() {
+        assert_eq!(3, assert_no_leaks(|| vec![1, 2, 3].len()));
+        let err = panic::catch_unwind(|| assert_no_leaks(|| Box::leak(Box::new([0_u8; 64]))))
+            .expect_err("must panic");
+        let msg = err.downcast_ref::<String>().expect("String payload");
+        assert!(msg.starts_with("memory leaked: allocated 64 bytes"), "{msg}");
+    }
+
+    #[test]
+    fn test_assert_max_alloc_bytes() {
+        assert_eq!(512, crate::assert_max_alloc_bytes!(1024, { vec![0_u8; 512].len() }));
+        let err = panic::catch_unwind(|| crate::assert_max_alloc_bytes!(100, { vec![0_u8; 512] }))
+            .expect_err("must panic");
+        let msg = err.downcast_ref::<String>().expect("String payload");
+        assert!(msg.starts_with("allocated more than 100 bytes"), "{msg}");
+    }
+}